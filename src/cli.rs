@@ -0,0 +1,1130 @@
+//! The `buckle` command line: buckle's own `--buckle-*` commands, and running buck2.
+
+use crate::config::{get_buck2_project_root, parse_env_file, read_config, BuckleConfig};
+use crate::download::{
+    cached_versions, current_version_dir, gc, get_buck2_dir, remove_cached_version,
+    unpack_compressed_binary, update_current_link, update_versioned_bin, verify_cached_binary,
+    GcPolicy, Installed, Source, BINARY, COMPRESSED_BINARY, PRELUDE_HASH_FILE,
+};
+use crate::error::BuckleError;
+use crate::http::{check_download_response, http_client, human_bytes, send, transfer_summary};
+use crate::platform::get_arch;
+use crate::prelude::{
+    find_prelude_paths, get_expected_prelude_hash, load_buckconfig, mismatched_prelude_msg,
+    prelude_fix, prelude_hash_usable, verify_prelude,
+};
+use crate::releases::{local_releases_path, read_local_releases, releases_api_url, Release};
+use crate::resolve::resolve_version;
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::io::Write;
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+use tempfile::NamedTempFile;
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+
+pub(crate) const BUCKLE_LATEST_RELEASE_URL: &str =
+    "https://api.github.com/repos/benbrittain/buckle/releases/latest";
+
+/// What `--buckle-info` prints, as JSON.
+///
+/// Tooling builds on this output, so fields may be added but must not be renamed or removed.
+#[derive(Debug, Serialize)]
+pub(crate) struct BuckleInfo {
+    /// buckle's own version.
+    pub(crate) buckle_version: &'static str,
+    /// The buck2 version as configured, e.g. `latest`.
+    pub(crate) requested_version: String,
+    /// The release it resolved to and was downloaded from.
+    pub(crate) resolved_version: String,
+    /// The target triple of the downloaded artifact.
+    pub(crate) target: String,
+    /// The cached buck2 binary, or its `buck2.zst` archive with `cache_compression`.
+    pub(crate) binary: PathBuf,
+    /// The directory buckle caches everything in.
+    pub(crate) cache_dir: PathBuf,
+    /// Whether the binary was already cached before this invocation.
+    pub(crate) cache_hit: bool,
+    /// The `.buckleconfig.toml` in effect, if any.
+    pub(crate) config_file: Option<PathBuf>,
+    /// The prelude commit this buck2 release expects, if known.
+    pub(crate) prelude_hash: Option<String>,
+}
+
+impl BuckleInfo {
+    pub(crate) fn new(config: &BuckleConfig, installed: &Installed) -> Result<BuckleInfo, Error> {
+        let prelude_hash = fs::read_to_string(installed.dir.join(PRELUDE_HASH_FILE))
+            .ok()
+            .map(|hash| hash.trim().to_owned());
+        Ok(BuckleInfo {
+            buckle_version: env!("CARGO_PKG_VERSION"),
+            requested_version: config.buck2_version.clone(),
+            resolved_version: installed.tag.clone(),
+            target: get_arch(&config.targets)?,
+            binary: installed.binary(),
+            cache_dir: config.buckle_dir.clone(),
+            cache_hit: installed.cache_hit,
+            config_file: config.config_file.clone(),
+            prelude_hash,
+        })
+    }
+}
+
+/// Check that buckle can run in this environment and print what it found.
+///
+/// Returns whether everything looked healthy.
+pub(crate) fn self_check() -> bool {
+    let mut problems = vec![];
+    println!("buckle {}", env!("CARGO_PKG_VERSION"));
+
+    match get_buck2_project_root() {
+        Ok(Some(root)) => println!("project root: {}", root.display()),
+        Ok(None) => println!("project root: none found (no .buckconfig or .buckroot)"),
+        Err(e) => {
+            println!("project root: {e}");
+            problems.push(e.to_string());
+        }
+    }
+
+    let config = match read_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("configuration: failed to read ({e})");
+            problems.push(format!("failed to read configuration: {e}"));
+            return report_self_check(&problems);
+        }
+    };
+    match &config.config_file {
+        Some(path) => println!("config file: {}", path.display()),
+        None => println!("config file: none found, using defaults"),
+    }
+    println!("buck2_version: {}", config.buck2_version);
+    println!("base_download_url: {}", config.base_download_url);
+    println!("check_prelude: {}", config.check_prelude);
+
+    let writable = fs::create_dir_all(&config.buckle_dir)
+        .and_then(|_| NamedTempFile::new_in(&config.buckle_dir))
+        .map(|_| ());
+    match writable {
+        Ok(()) => println!("cache dir: {} (writable)", config.buckle_dir.display()),
+        Err(e) => {
+            println!(
+                "cache dir: {} (not writable: {e})",
+                config.buckle_dir.display()
+            );
+            problems.push(format!(
+                "cache dir {} is not writable: {e}",
+                config.buckle_dir.display()
+            ));
+        }
+    }
+
+    let releases_url = releases_api_url(&config);
+    let reachable = if let Some(local) = local_releases_path(&config) {
+        read_local_releases(&local)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    } else {
+        http_client(&config)
+            .map_err(|e| e.to_string())
+            .and_then(|client| {
+                send(
+                    client
+                        .get(&releases_url)
+                        .map_err(|e| e.to_string())?
+                        .timeout(std::time::Duration::from_secs(10)),
+                )
+                .map_err(|e| e.to_string())
+            })
+            .and_then(|resp| match resp.status() {
+                status if status.is_success() => Ok(()),
+                status => Err(format!("status {status}")),
+            })
+    };
+    match reachable {
+        Ok(()) => println!("releases API: reachable"),
+        Err(e) => {
+            println!("releases API: unreachable ({e})");
+            // Only resolving "latest" needs the releases API.
+            if config.buck2_version == "latest" {
+                problems.push(format!("releases API {releases_url} is unreachable: {e}"));
+            }
+        }
+    }
+
+    match resolve_version(&config, &config.buckle_dir) {
+        Ok(resolved) => {
+            let dir = resolved
+                .dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy();
+            println!("resolved version: {dir}");
+            let buck2_path = resolved.dir.join(BINARY);
+            if buck2_path.exists() {
+                println!("cached binary: {}", buck2_path.display());
+            } else {
+                println!("cached binary: not yet downloaded");
+            }
+        }
+        Err(e) => {
+            println!("resolved version: failed ({e})");
+            problems.push(format!("failed to resolve buck2 version: {e}"));
+        }
+    }
+
+    report_self_check(&problems)
+}
+
+pub(crate) fn report_self_check(problems: &[String]) -> bool {
+    if problems.is_empty() {
+        println!("everything looks healthy");
+        return true;
+    }
+    println!("{} problem(s) found:", problems.len());
+    for problem in problems {
+        println!("  - {problem}");
+    }
+    false
+}
+
+/// The asset of a buckle release to upgrade to on `target`. cargo-dist publishes one
+/// `buckle-<version>-<target>.tar.xz` per unix platform; newer versions leave out the version.
+pub(crate) fn select_upgrade_asset<'a>(names: &[&'a str], target: &str) -> Option<&'a str> {
+    names.iter().copied().find(|name| {
+        let Some(stem) = name
+            .strip_prefix("buckle-")
+            .and_then(|name| name.strip_suffix(".tar.xz"))
+        else {
+            return false;
+        };
+        stem == target
+            || stem
+                .split_once('-')
+                .is_some_and(|(version, rest)| version.starts_with('v') && rest == target)
+    })
+}
+
+/// Replace the running buckle with the newest release of buckle.
+pub(crate) fn self_upgrade(config: &BuckleConfig) -> Result<(), Error> {
+    const INSTRUCTIONS: &str = "To upgrade by hand, re-run the installer from https://github.com/benbrittain/buckle/releases/latest or `cargo install buckle`.";
+    if cfg!(windows) {
+        return Err(anyhow!(
+            "--buckle-upgrade is not supported on Windows. {INSTRUCTIONS}"
+        ));
+    }
+
+    let exe = env::current_exe()?.canonicalize()?;
+    let exe_dir = exe
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent directory", exe.display()))?;
+    // The new binary is written next to the old one so it can be renamed over it atomically.
+    let mut new_exe = NamedTempFile::new_in(exe_dir).map_err(|e| {
+        anyhow!(
+            "can't replace {} because {} is not writable ({e}). {INSTRUCTIONS}",
+            exe.display(),
+            exe_dir.display()
+        )
+    })?;
+
+    let client = http_client(config)?;
+    let resp = send(client.get(BUCKLE_LATEST_RELEASE_URL)?)?;
+    let resp = check_download_response(BUCKLE_LATEST_RELEASE_URL, resp)?;
+    let release: Release = resp.json()?;
+    let current = format!("v{}", env!("CARGO_PKG_VERSION"));
+    if release.tag_name == current {
+        info!("buckle {current} is already the newest release");
+        return Ok(());
+    }
+
+    // buckle's own builds don't follow `[targets]`, which is about buck2's.
+    let target = get_arch(&Default::default())?;
+    let names: Vec<&str> = release
+        .assets
+        .iter()
+        .filter_map(|asset| asset["name"].as_str())
+        .collect();
+    let name = select_upgrade_asset(&names, &target).ok_or_else(|| {
+        anyhow!(
+            "buckle {} has no release for {target}. {INSTRUCTIONS}",
+            release.tag_name
+        )
+    })?;
+    let url = release
+        .assets
+        .iter()
+        .find(|asset| asset["name"] == name)
+        .and_then(|asset| asset["browser_download_url"].as_str())
+        .ok_or_else(|| anyhow!("the release asset {name} has no download URL"))?;
+
+    info!("fetching buckle {}", release.tag_name);
+    let started = std::time::Instant::now();
+    let resp = check_download_response(url, send(client.get(url)?)?)?;
+    let archive = resp.bytes()?;
+    info!(
+        "fetched buckle ({})",
+        transfer_summary(archive.len() as u64, started.elapsed())
+    );
+
+    let mut tarball = vec![];
+    lzma_rs::xz_decompress(&mut &archive[..], &mut tarball)
+        .map_err(|e| anyhow!("failed to decompress {name}: {e}"))?;
+    let mut found = false;
+    for entry in tar::Archive::new(&tarball[..]).entries()? {
+        let mut entry = entry?;
+        if entry.path()?.file_name() == Some(std::ffi::OsStr::new("buckle")) {
+            std::io::copy(&mut entry, &mut new_exe)?;
+            found = true;
+            break;
+        }
+    }
+    if !found {
+        return Err(anyhow!("{name} does not contain a buckle binary"));
+    }
+    new_exe.flush()?;
+    #[cfg(unix)]
+    fs::set_permissions(new_exe.path(), fs::Permissions::from_mode(0o755))?;
+    new_exe.persist(&exe)?;
+    info!(
+        "upgraded {} from {current} to {}",
+        exe.display(),
+        release.tag_name
+    );
+    Ok(())
+}
+
+/// The exit code for buckle's own failures (configuration, download, cache). It is distinct from
+/// buck2's exit codes, which are passed through, so scripts can tell the two apart.
+pub const BUCKLE_ERROR_EXIT_CODE: i32 = 69;
+
+/// Quote `arg` for a POSIX shell, leaving it alone if it needs no quoting.
+pub(crate) fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c));
+    if plain {
+        arg.to_owned()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+/// Whether the environment variable `name` matches an `env_remove` pattern, where `*` matches any
+/// run of characters, e.g. `BUCK2_*`.
+pub(crate) fn env_pattern_matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No `*` at all.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// buckle's commands, which replace running buck2. At most one may be given.
+pub(crate) const BUCKLE_COMMANDS: &[&str] = &[
+    "--buckle-version",
+    "--buckle-self-check",
+    "--buckle-upgrade",
+    "--buckle-print-config",
+    "--buckle-which",
+    "--buckle-download",
+    "--buckle-info",
+    "--buckle-audit",
+    "--buckle-gc",
+    "--buckle-cache-list",
+    "--buckle-cache-remove",
+];
+
+/// buckle's options, which adjust how buck2 is run. `--buckle-env-file=<path>` is one too, but
+/// takes a value.
+pub(crate) const BUCKLE_OPTIONS: &[&str] = &[
+    "--buckle-no-prelude-check",
+    "--buckle-verbose",
+    "--buckle-quiet",
+    "--buckle-reresolve",
+];
+
+/// Split buckle's own `--buckle-*` arguments from the ones meant for buck2.
+///
+/// Only flags before buck2's first non-flag argument (normally its subcommand) are looked at, so a
+/// `--buckle-*` argument further along, e.g. one for a `buck2 run` target, is passed through. A
+/// `--` among those leading flags ends buckle's arguments and is dropped; everything after it goes
+/// to buck2 unchanged.
+pub(crate) fn split_buckle_args(
+    argv: impl IntoIterator<Item = OsString>,
+) -> (Vec<String>, Vec<OsString>) {
+    let mut buckle_args = vec![];
+    let mut buck2_args = vec![];
+    let mut argv = argv.into_iter();
+    for arg in argv.by_ref() {
+        match arg.to_str() {
+            Some("--") => break,
+            Some(flag) if flag.starts_with("--buckle-") => buckle_args.push(flag.to_owned()),
+            // A lone `-` conventionally means stdin, so it is an argument rather than a flag.
+            Some(flag) if flag.starts_with('-') && flag != "-" => buck2_args.push(arg),
+            _ => {
+                buck2_args.push(arg);
+                break;
+            }
+        }
+    }
+    buck2_args.extend(argv);
+    (buckle_args, buck2_args)
+}
+
+/// `BUCKLE_INTERNAL` holds comma separated buckle arguments, with or without their `--buckle-`
+/// prefix, for wrappers that can't put them on the command line.
+pub(crate) fn internal_buckle_args(var: &str) -> Vec<String> {
+    var.split(',')
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| {
+            if arg.starts_with("--buckle-") {
+                arg.to_owned()
+            } else {
+                format!("--buckle-{arg}")
+            }
+        })
+        .collect()
+}
+
+/// Parse an age like `30d`. The units are `s`, `m`, `h`, `d` and `w`; a bare number is seconds.
+pub(crate) fn parse_age(age: &str) -> Result<std::time::Duration, Error> {
+    let (number, unit) = age.split_at(age.trim_end_matches(char::is_alphabetic).len());
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(anyhow!("invalid age '{age}'. Use e.g. 30d, 12h or 2w.")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid age '{age}'. Use e.g. 30d, 12h or 2w."))?;
+    Ok(std::time::Duration::from_secs(number.saturating_mul(scale)))
+}
+
+/// Parse a size like `5G`. The units are `K`, `M` and `G`, in powers of 1024, optionally followed
+/// by `B` or `iB`; a bare number is bytes.
+pub(crate) fn parse_size(size: &str) -> Result<u64, Error> {
+    let (number, unit) = size.split_at(size.trim_end_matches(char::is_alphabetic).len());
+    let scale = match unit.to_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        _ => return Err(anyhow!("invalid size '{size}'. Use e.g. 5G or 500M.")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid size '{size}'. Use e.g. 5G or 500M."))?;
+    Ok(number.saturating_mul(scale))
+}
+
+/// `--buckle-gc`: remove cached versions, keeping the current one. `args` may limit it to
+/// versions unused for `--older-than=<age>`, or to as many as fit in `--max-size=<size>`.
+fn collect_garbage(config: &BuckleConfig, args: &[OsString]) -> Result<(), Error> {
+    let mut policy = GcPolicy::default();
+    for arg in args {
+        let arg = arg.to_string_lossy();
+        if let Some(age) = arg.strip_prefix("--older-than=") {
+            policy.older_than = Some(parse_age(age)?);
+        } else if let Some(size) = arg.strip_prefix("--max-size=") {
+            policy.max_size = Some(parse_size(size)?);
+        } else {
+            return Err(anyhow!(
+                "unknown --buckle-gc option {arg}. Use --older-than=<age> or --max-size=<size>."
+            ));
+        }
+    }
+    if !config.buckle_dir.exists() {
+        info!("removed 0 cached version(s), reclaimed 0 B");
+        return Ok(());
+    }
+    let keep = current_version_dir(&config.buckle_dir);
+    let removed = gc(&config.buckle_dir, keep.as_deref(), &policy)?;
+    for (dir, size) in &removed {
+        info!("removed {} ({})", dir.display(), human_bytes(*size as f64));
+    }
+    let reclaimed: u64 = removed.iter().map(|(_, size)| size).sum();
+    info!(
+        "removed {} cached version(s), reclaimed {}",
+        removed.len(),
+        human_bytes(reclaimed as f64)
+    );
+    Ok(())
+}
+
+/// Describe how long ago something happened, e.g. `3d ago`, in the largest whole unit.
+pub(crate) fn describe_age(elapsed: std::time::Duration) -> String {
+    const UNITS: [(&str, u64); 4] = [
+        ("w", 7 * 24 * 60 * 60),
+        ("d", 24 * 60 * 60),
+        ("h", 60 * 60),
+        ("m", 60),
+    ];
+    let secs = elapsed.as_secs();
+    match UNITS.iter().find(|(_, scale)| secs >= *scale) {
+        Some((unit, scale)) => format!("{}{unit} ago", secs / scale),
+        None => "just now".to_owned(),
+    }
+}
+
+/// `--buckle-cache-list`: print each cached version with its size and when it was last used, most
+/// recently used first.
+fn list_cache(config: &BuckleConfig) -> Result<(), Error> {
+    if !config.buckle_dir.exists() {
+        return Ok(());
+    }
+    let current = current_version_dir(&config.buckle_dir);
+    let mut versions = cached_versions(&config.buckle_dir)?;
+    versions.reverse();
+    let width = versions.iter().map(|v| v.tag.len()).max().unwrap_or(0);
+    let now = std::time::SystemTime::now();
+    for version in versions {
+        let age = now.duration_since(version.last_used).unwrap_or_default();
+        let marker = if Some(&version.dir) == current.as_ref() {
+            " (current)"
+        } else {
+            ""
+        };
+        println!(
+            "{:width$}  {:>9}  last used {:<8}  {}{marker}",
+            version.tag,
+            human_bytes(version.size as f64),
+            describe_age(age),
+            version.dir.display(),
+        );
+    }
+    Ok(())
+}
+
+/// `--buckle-cache-remove <tag>`: remove one cached version, which may not be the current one.
+fn remove_from_cache(config: &BuckleConfig, args: &[OsString]) -> Result<(), Error> {
+    let [tag] = args else {
+        return Err(anyhow!(
+            "--buckle-cache-remove takes the tag of one cached version. --buckle-cache-list shows them."
+        ));
+    };
+    let tag = tag.to_string_lossy();
+    let versions = if config.buckle_dir.exists() {
+        cached_versions(&config.buckle_dir)?
+    } else {
+        vec![]
+    };
+    let version = versions
+        .into_iter()
+        .find(|version| {
+            version.tag == tag || version.dir.file_name().is_some_and(|name| *name == *tag)
+        })
+        .ok_or_else(|| anyhow!("buck2 {tag} is not cached. --buckle-cache-list shows what is."))?;
+    if current_version_dir(&config.buckle_dir).as_ref() == Some(&version.dir) {
+        return Err(anyhow!(
+            "buck2 {tag} is the current version, so it can't be removed. Run another version first, or use --buckle-gc to remove every other one."
+        ));
+    }
+    remove_cached_version(&version.dir)?;
+    info!(
+        "removed {} ({})",
+        version.dir.display(),
+        human_bytes(version.size as f64)
+    );
+    Ok(())
+}
+
+fn cache_corrupted(config: &BuckleConfig) -> Error {
+    BuckleError::CacheCorrupt {
+        path: config.buckle_dir.clone(),
+        reason: "The buckle cache is corrupted".to_owned(),
+    }
+    .into()
+}
+
+/// Run `buckle` as the binary does: report a failure and exit with `BUCKLE_ERROR_EXIT_CODE`.
+pub fn main() {
+    if let Err(e) = run() {
+        error!("{e:?}");
+        std::process::exit(BUCKLE_ERROR_EXIT_CODE);
+    }
+}
+
+/// Run `buckle` with the process's arguments and environment.
+pub fn run() -> Result<(), Error> {
+    // Collect information indented for buck2 binary.
+    let (mut buckle_args, args) = split_buckle_args(env::args_os().skip(1));
+    if let Ok(internal) = env::var("BUCKLE_INTERNAL") {
+        buckle_args.extend(internal_buckle_args(&internal));
+    }
+    if let Some(unknown) = buckle_args.iter().find(|arg| {
+        !BUCKLE_COMMANDS.contains(&arg.as_str())
+            && !BUCKLE_OPTIONS.contains(&arg.as_str())
+            && !arg.starts_with("--buckle-env-file=")
+    }) {
+        return Err(anyhow!(
+            "unknown buckle option {unknown}. Pass `--` first to give it to buck2 instead."
+        ));
+    }
+    let mut commands = buckle_args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| BUCKLE_COMMANDS.contains(arg));
+    let command = commands.next();
+    if let Some(other) = commands.next() {
+        return Err(anyhow!(
+            "{} and {other} can't be used together",
+            command.unwrap_or_default()
+        ));
+    }
+    // `--version` is buck2's, so buckle's own version has a flag of its own. It needs nothing
+    // else, which keeps it working for bug reports about a broken setup.
+    if command == Some("--buckle-version") {
+        println!("buckle {}", env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    let has_option = |option: &str| buckle_args.iter().any(|arg| arg == option);
+    if has_option("--buckle-quiet")
+        || env::var("BUCKLE_QUIET").is_ok_and(|var| var == "1" || var.to_uppercase() == "YES")
+    {
+        crate::output::set_quiet();
+    }
+    let no_prelude_check = has_option("--buckle-no-prelude-check");
+    let verbose = has_option("--buckle-verbose")
+        || env::var("BUCKLE_VERBOSE").is_ok_and(|var| var == "1" || var.to_uppercase() == "YES");
+    // `exec` replaces buckle with buck2 instead of waiting for it as a child.
+    let exec = match env::var("BUCKLE_EXEC_MODE").as_deref() {
+        Err(_) | Ok("") | Ok("spawn") => false,
+        Ok("exec") => true,
+        Ok(other) => {
+            return Err(anyhow!(
+                "BUCKLE_EXEC_MODE must be 'spawn' or 'exec', not '{other}'"
+            ))
+        }
+    };
+    if command == Some("--buckle-self-check") {
+        if !self_check() {
+            return Err(anyhow!("the self-check found problems, listed above"));
+        }
+        return Ok(());
+    }
+
+    let mut config = read_config()?;
+    if no_prelude_check {
+        config.check_prelude = false;
+    }
+    config.reresolve = has_option("--buckle-reresolve");
+    if let Some(env_file) = buckle_args
+        .iter()
+        .rev()
+        .find_map(|arg| arg.strip_prefix("--buckle-env-file="))
+    {
+        config.env_file = Some(PathBuf::from(env_file));
+    }
+    if command == Some("--buckle-print-config") {
+        print!("{}", toml::to_string(&config)?);
+        return Ok(());
+    }
+    if command == Some("--buckle-upgrade") {
+        return self_upgrade(&config);
+    }
+    if command == Some("--buckle-gc") {
+        return collect_garbage(&config, &args);
+    }
+    if command == Some("--buckle-cache-list") {
+        return list_cache(&config);
+    }
+    if command == Some("--buckle-cache-remove") {
+        return remove_from_cache(&config, &args);
+    }
+
+    let installed = get_buck2_dir(&config)?;
+    let buck2_dir = installed.dir.clone();
+    let current_dir = config.buckle_dir.join("current");
+    if let Err(e) = update_current_link(&current_dir, &buck2_dir) {
+        warn!("failed to update {}: {e}", current_dir.display());
+    }
+    // A version downloaded compressed before `versioned_bin` was turned on has nothing to link.
+    if config.versioned_bin && !installed.is_compressed() {
+        if let Err(e) = update_versioned_bin(&config.buckle_dir, &buck2_dir) {
+            warn!(
+                "failed to update {}: {e}",
+                config.buckle_dir.join("bin").display()
+            );
+        }
+    }
+    if command == Some("--buckle-which") {
+        if installed.is_compressed() {
+            return Err(anyhow!(
+                "buck2 {} is cached compressed (cache_compression), so there is no binary for --buckle-which to print. Turn cache_compression off and remove {} to get a plain buck2.",
+                installed.tag,
+                buck2_dir.display()
+            ));
+        }
+        println!("{}", current_dir.join(BINARY).display());
+        return Ok(());
+    }
+    if command == Some("--buckle-download") {
+        info!(
+            "buck2 {} is cached at {}",
+            installed.tag,
+            installed.binary().display()
+        );
+        return Ok(());
+    }
+    if command == Some("--buckle-info") {
+        let info = BuckleInfo::new(&config, &installed)?;
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    if command == Some("--buckle-audit") {
+        let source = Source::read(&installed.dir)?;
+        println!("{}", serde_json::to_string_pretty(&source)?);
+        return Ok(());
+    }
+
+    let mut buck2_path: PathBuf = [&buck2_dir, Path::new(BINARY)].iter().collect();
+    // Only what was downloaded with `cache_compression` is stored compressed, whatever the
+    // setting is now.
+    let unpacked = if !buck2_path.exists() && buck2_dir.join(COMPRESSED_BINARY).exists() {
+        let unpacked = unpack_compressed_binary(&buck2_dir)?;
+        buck2_path = unpacked.to_path_buf();
+        Some(unpacked)
+    } else {
+        None
+    };
+    if !buck2_path.exists() {
+        return Err(cache_corrupted(&config));
+    }
+
+    // mode() is only available on unix systems. Some network filesystems don't keep the
+    // executable bit although the file runs fine, hence the escape hatch.
+    #[cfg(unix)]
+    if buck2_path.exists()
+        && !env::var("BUCKLE_SKIP_EXEC_CHECK")
+            .is_ok_and(|var| var == "1" || var.to_uppercase() == "YES")
+    {
+        let is_exec = |path: &Path| -> Result<bool, Error> {
+            let metadata = path.metadata()?;
+            Ok(metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        };
+        if !is_exec(&buck2_path)? {
+            // A lost executable bit is easily restored, e.g. after the cache was copied around.
+            let mode = buck2_path.metadata()?.permissions().mode();
+            let repaired =
+                fs::set_permissions(&buck2_path, fs::Permissions::from_mode(mode | 0o111)).is_ok()
+                    && is_exec(&buck2_path)?;
+            if !repaired {
+                return Err(cache_corrupted(&config));
+            }
+            warn!(
+                "{} was not executable; restored its executable bit",
+                buck2_path.display()
+            );
+        }
+    }
+
+    if env::var("BUCKLE_VERIFY_EACH_RUN").is_ok_and(|var| var == "1" || var.to_uppercase() == "YES")
+    {
+        verify_cached_binary(&installed, &buck2_path)?;
+    }
+
+    if config.check_prelude {
+        // If we can't find the project root, just skip checking the prelude and call the buck2 binary
+        if let Some(root) = get_buck2_project_root()? {
+            // If we fail to parse the ini file, don't throw an error. We can't parse it for
+            // some reason, so we should fall back on buck2 to throw a better error.
+            let buck2config: PathBuf = [root, Path::new(".buckconfig")].iter().collect();
+            if let Ok(ini) = load_buckconfig(&buck2config) {
+                let prelude_paths = find_prelude_paths(
+                    &ini,
+                    config.prelude_section.as_deref(),
+                    &config.prelude_hashes,
+                );
+                // Cells without a configured hash are checked against the release's.
+                let release_hash = if prelude_paths
+                    .iter()
+                    .any(|(cell, _)| !config.prelude_hashes.contains_key(*cell))
+                    && prelude_hash_usable(&config, &installed)
+                {
+                    Some(get_expected_prelude_hash(&installed)?)
+                } else {
+                    None
+                };
+                // A mismatched prelude is worth a warning, but buck2 may well cope with it,
+                // unless the check is strict. Every prelude is checked, so all mismatches are
+                // reported at once.
+                let mut mismatches = vec![];
+                for (cell, prelude_path) in prelude_paths {
+                    let Some(expected_hash) =
+                        config.prelude_hashes.get(cell).or(release_hash.as_ref())
+                    else {
+                        continue;
+                    };
+                    if let Err(e) = verify_prelude(prelude_path, expected_hash) {
+                        match e.downcast_ref() {
+                            Some(BuckleError::PreludeMismatch { path, expected, .. })
+                                if config.prelude_check_strict =>
+                            {
+                                mismatches.push(format!("{e}\n{}", prelude_fix(path, expected)))
+                            }
+                            Some(BuckleError::PreludeMismatch {
+                                path,
+                                found,
+                                expected,
+                            }) => mismatched_prelude_msg(path, found, expected),
+                            _ => return Err(e),
+                        }
+                    }
+                }
+                if !mismatches.is_empty() {
+                    return Err(anyhow!(
+                        "{}\nNot running buck2, since prelude_check_strict is set.",
+                        mismatches.join("\n")
+                    ));
+                }
+            }
+        }
+    }
+
+    // The env file's variables come between the inherited ones and `[env]`.
+    let mut file_env = BTreeMap::new();
+    if let Some(env_file) = &config.env_file {
+        let contents = fs::read_to_string(env_file)
+            .map_err(|e| anyhow!("failed to read env file {}: {e}", env_file.display()))?;
+        file_env.extend(
+            parse_env_file(&contents)
+                .map_err(|e| anyhow!("invalid env file {}: {e}", env_file.display()))?
+                .into_iter()
+                .filter(|(key, _)| !config.env.contains_key(key)),
+        );
+    }
+    // Configured variables win over inherited ones of the same name, and over `env_remove`.
+    let mut envs: Vec<(OsString, OsString)> = env::vars_os()
+        .filter(|(key, _)| {
+            !key.to_str().is_some_and(|key| {
+                config.env.contains_key(key)
+                    || file_env.contains_key(key)
+                    || config
+                        .env_remove
+                        .iter()
+                        .any(|pattern| env_pattern_matches(pattern, key))
+            })
+        })
+        .collect();
+    envs.extend(
+        file_env
+            .iter()
+            .chain(&config.env)
+            .map(|(key, value)| (OsString::from(key), OsString::from(value))),
+    );
+    // Configured arguments go first, since buck2's global flags must precede the subcommand. The
+    // user's own come after them, so where buck2 lets a flag repeat, the user's one wins.
+    let args: Vec<OsString> = config
+        .default_args
+        .iter()
+        .map(OsString::from)
+        .chain(args)
+        .collect();
+    // A wrapper is run instead, with buck2 and its arguments after the wrapper's own.
+    let (program, args) = match config.exec_wrapper.split_first() {
+        Some((wrapper, wrapper_args)) => (
+            PathBuf::from(wrapper),
+            wrapper_args
+                .iter()
+                .map(OsString::from)
+                .chain([buck2_path.clone().into_os_string()])
+                .chain(args)
+                .collect(),
+        ),
+        None => (buck2_path.clone(), args),
+    };
+    if verbose {
+        let mut line = shell_quote(&program.as_os_str().to_string_lossy());
+        for arg in &args {
+            line.push(' ');
+            line.push_str(&shell_quote(&arg.to_string_lossy()));
+        }
+        info!("running {line} ({} environment variables)", envs.len());
+    }
+
+    // Pass all file descriptors through as well.
+    let mut buck2 = Command::new(&program);
+    buck2
+        .args(args)
+        .env_clear()
+        .envs(envs)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit());
+    // buck2 shows its program name in errors and help, where the cache path is just noise.
+    // With a wrapper, buck2's program name is up to the wrapper.
+    #[cfg(unix)]
+    if !config.real_arg0 && config.exec_wrapper.is_empty() {
+        use std::os::unix::process::CommandExt;
+        buck2.arg0("buck2");
+    }
+    if exec {
+        if unpacked.is_some() {
+            // Nothing would be left to remove the unpacked binary once buck2 exits.
+            warn!("BUCKLE_EXEC_MODE=exec is ignored for binaries stored with cache_compression");
+        } else if cfg!(unix) {
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                let e = buck2.exec();
+                return Err(anyhow!("Failed to execute {}: {e}", program.display()));
+            }
+        } else {
+            warn!("BUCKLE_EXEC_MODE=exec is only supported on unix");
+        }
+    }
+    let status = buck2
+        .output()
+        .map_err(|e| anyhow!("Failed to execute {}: {e}", program.display()))?
+        .status;
+    // Exiting skips destructors, so remove the unpacked binary now.
+    drop(unpacked);
+
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_upgrade_asset() {
+        let names = [
+            "buckle-installer.sh",
+            "buckle-v1.2.0-x86_64-unknown-linux-gnu.tar.xz",
+            "buckle-v1.2.0-x86_64-unknown-linux-gnu.tar.xz.sha256",
+            "buckle-v1.2.0-x86_64-unknown-linux-musl.tar.xz",
+            "buckle-v1.2.0-aarch64-apple-darwin.tar.xz",
+            "buckle-v1.2.0-x86_64-pc-windows-msvc.zip",
+        ];
+        assert_eq!(
+            select_upgrade_asset(&names, "x86_64-unknown-linux-gnu"),
+            Some("buckle-v1.2.0-x86_64-unknown-linux-gnu.tar.xz")
+        );
+        assert_eq!(
+            select_upgrade_asset(&names, "x86_64-unknown-linux-musl"),
+            Some("buckle-v1.2.0-x86_64-unknown-linux-musl.tar.xz")
+        );
+        assert_eq!(
+            select_upgrade_asset(&names, "aarch64-apple-darwin"),
+            Some("buckle-v1.2.0-aarch64-apple-darwin.tar.xz")
+        );
+        // No artifact for this platform, and a prefix of a triple doesn't count.
+        assert_eq!(
+            select_upgrade_asset(&names, "aarch64-unknown-linux-gnu"),
+            None
+        );
+        assert_eq!(select_upgrade_asset(&names, "linux-gnu"), None);
+
+        let unversioned = ["buckle-aarch64-unknown-linux-gnu.tar.xz"];
+        assert_eq!(
+            select_upgrade_asset(&unversioned, "aarch64-unknown-linux-gnu"),
+            Some("buckle-aarch64-unknown-linux-gnu.tar.xz")
+        );
+    }
+
+    #[test]
+    fn test_env_pattern_matches() {
+        assert!(env_pattern_matches("FOO", "FOO"));
+        assert!(!env_pattern_matches("FOO", "FOOBAR"));
+        assert!(env_pattern_matches("BUCK2_*", "BUCK2_HARD_ERROR"));
+        assert!(env_pattern_matches("BUCK2_*", "BUCK2_"));
+        assert!(!env_pattern_matches("BUCK2_*", "MY_BUCK2_X"));
+        assert!(env_pattern_matches("*_TOKEN", "GITHUB_TOKEN"));
+        assert!(env_pattern_matches("A*B*C", "AxxBxxC"));
+        assert!(!env_pattern_matches("A*B*C", "AxxC"));
+        assert!(!env_pattern_matches("AB*BA", "ABA"));
+        assert!(env_pattern_matches("*", "ANYTHING"));
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("//foo:bar"), "//foo:bar");
+        assert_eq!(shell_quote("--config=a.b=c"), "--config=a.b=c");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+    }
+
+    fn split(argv: &[&str]) -> (Vec<String>, Vec<OsString>) {
+        split_buckle_args(argv.iter().map(OsString::from))
+    }
+
+    #[test]
+    fn test_split_buckle_args() {
+        let (buckle, buck2) = split(&["--buckle-no-prelude-check", "build", "//..."]);
+        assert_eq!(buckle, ["--buckle-no-prelude-check"]);
+        assert_eq!(buck2, ["build", "//..."]);
+
+        // buckle flags may sit among buck2's own leading flags.
+        let (buckle, buck2) = split(&["--isolation-dir=ci", "--buckle-verbose", "-v", "build"]);
+        assert_eq!(buckle, ["--buckle-verbose"]);
+        assert_eq!(buck2, ["--isolation-dir=ci", "-v", "build"]);
+
+        let (buckle, buck2) = split(&[]);
+        assert!(buckle.is_empty());
+        assert!(buck2.is_empty());
+    }
+
+    #[test]
+    fn test_split_buckle_args_after_subcommand() {
+        // Arguments after the first non-flag one belong to buck2.
+        let (buckle, buck2) = split(&["run", "//:tool", "--buckle-no-prelude-check"]);
+        assert!(buckle.is_empty());
+        assert_eq!(buck2, ["run", "//:tool", "--buckle-no-prelude-check"]);
+
+        let (buckle, buck2) = split(&["--buckle-verbose", "run", "//:tool", "--", "--buckle-info"]);
+        assert_eq!(buckle, ["--buckle-verbose"]);
+        assert_eq!(buck2, ["run", "//:tool", "--", "--buckle-info"]);
+    }
+
+    #[test]
+    fn test_split_buckle_args_separator() {
+        // A leading `--` hands everything after it to buck2 and is not passed on itself.
+        let (buckle, buck2) = split(&["--buckle-verbose", "--", "--buckle-which", "build"]);
+        assert_eq!(buckle, ["--buckle-verbose"]);
+        assert_eq!(buck2, ["--buckle-which", "build"]);
+
+        let (buckle, buck2) = split(&["--", "--"]);
+        assert!(buckle.is_empty());
+        assert_eq!(buck2, ["--"]);
+    }
+
+    #[test]
+    fn test_describe_age() {
+        use std::time::Duration;
+        assert_eq!(describe_age(Duration::from_secs(5)), "just now");
+        assert_eq!(describe_age(Duration::from_secs(90)), "1m ago");
+        assert_eq!(describe_age(Duration::from_secs(3 * 86400 + 7)), "3d ago");
+        assert_eq!(describe_age(Duration::from_secs(20 * 86400)), "2w ago");
+    }
+
+    #[test]
+    fn test_parse_age_and_size() {
+        use std::time::Duration;
+        assert_eq!(parse_age("30d").unwrap(), Duration::from_secs(30 * 86400));
+        assert_eq!(parse_age("2w").unwrap(), Duration::from_secs(14 * 86400));
+        assert_eq!(parse_age("90").unwrap(), Duration::from_secs(90));
+        assert!(parse_age("d").is_err());
+        assert!(parse_age("3y").is_err());
+        assert_eq!(parse_size("5G").unwrap(), 5 << 30);
+        assert_eq!(parse_size("500MiB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert!(parse_size("5T").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+
+    #[test]
+    fn test_split_buckle_args_cases() {
+        // (argv, buckle's arguments, buck2's arguments)
+        let cases: &[(&[&str], &[&str], &[&str])] = &[
+            (
+                &["run", "//foo", "--", "arg1", "arg2"],
+                &[],
+                &["run", "//foo", "--", "arg1", "arg2"],
+            ),
+            (
+                &[
+                    "--buckle-quiet",
+                    "run",
+                    "//foo",
+                    "--",
+                    "--buckle-verbose",
+                    "--",
+                ],
+                &["--buckle-quiet"],
+                &["run", "//foo", "--", "--buckle-verbose", "--"],
+            ),
+            (
+                &[
+                    "--buckle-quiet",
+                    "--buckle-verbose",
+                    "--",
+                    "run",
+                    "//foo",
+                    "--",
+                    "x",
+                ],
+                &["--buckle-quiet", "--buckle-verbose"],
+                &["run", "//foo", "--", "x"],
+            ),
+            (
+                &["--", "--buckle-quiet", "--", "run"],
+                &[],
+                &["--buckle-quiet", "--", "run"],
+            ),
+            (
+                &["-c", "--buckle-quiet", "build"],
+                &["--buckle-quiet"],
+                &["-c", "build"],
+            ),
+            (
+                &["--isolation-dir=ci", "--", "--isolation-dir=other"],
+                &[],
+                &["--isolation-dir=ci", "--isolation-dir=other"],
+            ),
+            (
+                &["build", "--buckle-quiet", "--", "--buckle-verbose"],
+                &[],
+                &["build", "--buckle-quiet", "--", "--buckle-verbose"],
+            ),
+            (&["--buckle-which"], &["--buckle-which"], &[]),
+            (&["--"], &[], &[]),
+            (&["-", "--buckle-quiet"], &[], &["-", "--buckle-quiet"]),
+        ];
+        for (argv, buckle, buck2) in cases {
+            let (found_buckle, found_buck2) = split(argv);
+            assert_eq!(found_buckle, *buckle, "buckle arguments of {argv:?}");
+            assert_eq!(found_buck2, *buck2, "buck2 arguments of {argv:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_split_buckle_args_non_utf8() {
+        use std::os::unix::ffi::OsStringExt;
+        let odd = OsString::from_vec(vec![0x66, 0x6f, 0x80]);
+        let argv = vec![odd.clone(), OsString::from("--buckle-which")];
+        let (buckle, buck2) = split_buckle_args(argv);
+        assert!(buckle.is_empty());
+        assert_eq!(buck2, [odd, OsString::from("--buckle-which")]);
+    }
+
+    #[test]
+    fn test_internal_buckle_args() {
+        assert_eq!(
+            internal_buckle_args("which, --buckle-verbose,,"),
+            ["--buckle-which", "--buckle-verbose"]
+        );
+        assert!(internal_buckle_args("").is_empty());
+    }
+}
@@ -0,0 +1,860 @@
+//! Where buckle's settings come from: environment variables, `.buckleconfig.toml`,
+//! `.buckle-version` and defaults, combined into a `BuckleConfig`.
+
+use crate::error::BuckleError;
+use anyhow::{anyhow, Error};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+pub(crate) const UPSTREAM_BASE_URL: &str = "https://github.com/facebook/buck2/releases/download";
+pub(crate) const GITHUB_API_BASE: &str = "https://api.github.com";
+
+/// The current directory, which every upward search starts from.
+///
+/// It can be gone by the time buckle runs, e.g. when CI cleans up a checkout under a running job.
+pub(crate) fn working_dir() -> Result<PathBuf, Error> {
+    env::current_dir().map_err(|e| {
+        anyhow!("could not determine the current directory, which may have been deleted: {e}")
+    })
+}
+
+/// Find the furthest .buckconfig except if a .buckroot is found.
+pub(crate) fn get_buck2_project_root() -> Result<Option<&'static Path>, Error> {
+    static INSTANCE: OnceCell<Option<PathBuf>> = OnceCell::new();
+    let path = INSTANCE.get_or_try_init(|| {
+        let path = working_dir()?;
+        let mut current_root = None;
+        for ancestor in path.ancestors() {
+            let mut br = ancestor.to_path_buf();
+            br.push(".buckroot");
+            if br.exists() {
+                // A buckroot means you should not check any higher in the file tree.
+                return Ok(Some(ancestor.to_path_buf()));
+            }
+
+            let mut bc = ancestor.to_path_buf();
+            bc.push(".buckconfig");
+            if bc.exists() {
+                // This is the highest buckconfig we know about
+                current_root = Some(ancestor.to_path_buf());
+            }
+        }
+        Ok::<_, Error>(current_root)
+    })?;
+    Ok(path.as_deref())
+}
+
+/// Whether we are running under Termux on Android, which reports itself as plain Linux.
+pub(crate) fn is_termux(var: impl Fn(&str) -> Option<String>) -> bool {
+    var("TERMUX_VERSION").is_some()
+        || var("PREFIX").is_some_and(|prefix| prefix.contains("/com.termux/"))
+}
+
+/// Termux's cache directory, if we are running under Termux and `$XDG_CACHE_HOME` doesn't say
+/// otherwise. Environment variables are looked up through `var`, which treats empty ones as unset.
+pub(crate) fn termux_cache_dir(var: impl Fn(&str) -> Option<String>) -> Option<PathBuf> {
+    if var("XDG_CACHE_HOME").is_some() {
+        return None;
+    }
+    // Termux apps may only write inside their sandbox, and `$PREFIX/var/cache` is where its own
+    // packages cache things.
+    let prefix = var("PREFIX").filter(|_| is_termux(&var))?;
+    let mut path = PathBuf::from(prefix);
+    path.push("var");
+    path.push("cache");
+    Some(path)
+}
+
+/// Whether `/proc/version` says we are running under WSL, whose kernel is built by Microsoft.
+pub(crate) fn is_wsl(proc_version: &str) -> bool {
+    proc_version.to_lowercase().contains("microsoft")
+}
+
+fn host_is_wsl() -> bool {
+    env::consts::OS == "linux" && is_wsl(&fs::read_to_string("/proc/version").unwrap_or_default())
+}
+
+/// A cache directory on WSL's own Linux filesystem, if `$HOME` is a Windows drive mounted under
+/// `/mnt/` and `$XDG_CACHE_HOME` doesn't say otherwise. Those mounts are slow and don't keep
+/// executable bits, so `/home/$USER/.cache` is used instead when that home directory exists.
+pub(crate) fn wsl_cache_dir(
+    var: impl Fn(&str) -> Option<String>,
+    exists: impl Fn(&Path) -> bool,
+) -> Option<PathBuf> {
+    if var("XDG_CACHE_HOME").is_some() || !var("HOME")?.starts_with("/mnt/") {
+        return None;
+    }
+    let home = Path::new("/home").join(var("USER")?);
+    exists(&home).then(|| home.join(".cache"))
+}
+
+/// The OS's default cache directory, following the platform's conventions (XDG on Linux).
+pub(crate) fn get_os_cache_dir() -> Result<PathBuf, Error> {
+    let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+    if env::consts::OS == "linux" {
+        if let Some(dir) = termux_cache_dir(var) {
+            return Ok(dir);
+        }
+        if host_is_wsl() {
+            if let Some(dir) = wsl_cache_dir(var, Path::exists) {
+                return Ok(dir);
+            }
+        }
+    }
+    directories::BaseDirs::new()
+        .map(|dirs| dirs.cache_dir().to_path_buf())
+        .ok_or_else(|| BuckleError::NoCacheDir.into())
+}
+
+/// Why running as `uid` would leave root-owned files in `buckle_dir` that other users can't
+/// replace, if it would: root writing into a directory owned by someone else or writable by all.
+///
+/// `buckle_dir` doesn't have to exist yet; its nearest existing ancestor is looked at instead.
+#[cfg(unix)]
+pub(crate) fn root_cache_warning(uid: u32, buckle_dir: &Path) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    if uid != 0 {
+        return None;
+    }
+    let (dir, meta) = buckle_dir
+        .ancestors()
+        .find_map(|dir| Some((dir, fs::metadata(dir).ok()?)))?;
+    let reason = if meta.uid() != 0 {
+        format!("{} is owned by uid {}", dir.display(), meta.uid())
+    } else if meta.mode() & 0o002 != 0 {
+        format!("{} is world-writable", dir.display())
+    } else {
+        return None;
+    };
+    Some(format!(
+        "running as root with the cache in {}, but {reason}. Files buckle writes there will be owned by root and break later non-root runs; set BUCKLE_CACHE to a directory only root uses.",
+        buckle_dir.display()
+    ))
+}
+
+/// The time files written into the cache are stamped with, from `SOURCE_DATE_EPOCH`, so that
+/// reproducible image builds get the same cache layer every time.
+pub(crate) fn source_date_epoch() -> Option<SystemTime> {
+    let secs = env::var("SOURCE_DATE_EPOCH").ok()?.trim().parse().ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Write `contents` to `path` through a temporary file next to it that is renamed over it, so a
+/// reader sees the old file or the new one, never a half-written one.
+pub(crate) fn write_atomically(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+    let dir = path
+        .parent()
+        .ok_or(anyhow!("{} has no parent directory", path.display()))?;
+    let tmp = tempfile::NamedTempFile::new_in(dir)?;
+    fs::write(tmp.path(), contents)?;
+    // Temporary files are only readable by their owner, unlike what `fs::write` would create.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(tmp.path(), fs::Permissions::from_mode(0o644))?;
+    }
+    tmp.persist(path)?;
+    Ok(())
+}
+
+/// Set the modification time of the file or directory at `path`.
+pub(crate) fn set_mtime(path: &Path, time: SystemTime) -> Result<(), Error> {
+    let file = if path.is_dir() {
+        fs::File::open(path)?
+    } else {
+        fs::File::options().write(true).open(path)?
+    };
+    file.set_modified(time)
+        .map_err(|e| anyhow!("failed to set the time of {}: {e}", path.display()))
+}
+
+/// The version in `dir`'s `.buckle-version`, if it has one.
+///
+/// The file holds a single version, `latest`, or a constraint like `>=2023-07-01`. Blank lines and
+/// lines starting with `#` are ignored.
+fn read_buckle_version_file(dir: &Path) -> Result<Option<String>, Error> {
+    let path = dir.join(".buckle-version");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path)?;
+    let version = contents
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .ok_or_else(|| anyhow!("{} doesn't name a buck2 version", path.display()))?;
+    if version.starts_with(['>', '<', '=']) {
+        min_release_date(version)?;
+    }
+    Ok(Some(version.to_owned()))
+}
+
+/// The `buck2` version in asdf's `.tool-versions` format: one `<tool> <version>...` line per
+/// tool, with `#` starting a comment. asdf falls back on later versions if the first isn't
+/// installed, which doesn't apply to buckle, so only the first is used.
+pub(crate) fn tool_versions_buck2(contents: &str) -> Option<&str> {
+    contents.lines().find_map(|line| {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        (fields.next() == Some("buck2"))
+            .then(|| fields.next())
+            .flatten()
+    })
+}
+
+/// The buck2 version in `dir`'s `.tool-versions`, if it has one with a `buck2` entry. Like asdf,
+/// files without one are skipped.
+fn read_tool_versions(dir: &Path) -> Result<Option<String>, Error> {
+    let path = dir.join(".tool-versions");
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(tool_versions_buck2(&fs::read_to_string(&path)?).map(str::to_owned))
+}
+
+/// The buck2 version from the version file closest to the current directory, if any.
+///
+/// Each directory from the current one up is checked for `.buckle-version`, then for
+/// `buck2_version` in `config_file` if it lives there, then for a `buck2` entry in
+/// `.tool-versions`. The first one found wins, so a project's own files aren't overridden by ones
+/// further up the tree.
+pub(crate) fn nearest_buck2_version(
+    config_file: Option<&Path>,
+    config_version: Option<&str>,
+) -> Result<Option<String>, Error> {
+    for dir in working_dir()?.ancestors() {
+        if let Some(version) = read_buckle_version_file(dir)? {
+            return Ok(Some(version));
+        }
+        if let Some(version) =
+            config_version.filter(|_| config_file.and_then(Path::parent) == Some(dir))
+        {
+            return Ok(Some(version.to_owned()));
+        }
+        if let Some(version) = read_tool_versions(dir)? {
+            return Ok(Some(version));
+        }
+    }
+    Ok(None)
+}
+
+/// The variables in a dotenv-style file: `KEY=value` lines, optionally starting with `export`.
+/// Blank lines and lines starting with `#` are skipped, as is a ` #` comment after an unquoted
+/// value. Single quoted values are taken literally; double quoted ones understand `\n`, `\t`, `\"`
+/// and `\\`.
+pub(crate) fn parse_env_file(contents: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut vars = vec![];
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let invalid = |why: &str| anyhow!("line {}: {why}", number + 1);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected KEY=value"))?;
+        let key = key.trim();
+        if key.is_empty() || key.contains(char::is_whitespace) {
+            return Err(invalid(&format!("invalid variable name '{key}'")));
+        }
+        let value = value.trim_start();
+        let value = if let Some(quoted) = value.strip_prefix('\'') {
+            let (value, _) = quoted
+                .split_once('\'')
+                .ok_or_else(|| invalid("unterminated ' quote"))?;
+            value.to_owned()
+        } else if let Some(quoted) = value.strip_prefix('"') {
+            let mut unquoted = String::new();
+            let mut chars = quoted.chars();
+            loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => match chars.next() {
+                        Some('n') => unquoted.push('\n'),
+                        Some('t') => unquoted.push('\t'),
+                        Some(c) => unquoted.push(c),
+                        None => return Err(invalid("unterminated \" quote")),
+                    },
+                    Some(c) => unquoted.push(c),
+                    None => return Err(invalid("unterminated \" quote")),
+                }
+            }
+            unquoted
+        } else {
+            value
+                .split_once(" #")
+                .map_or(value, |(value, _)| value)
+                .trim_end()
+                .to_owned()
+        };
+        vars.push((key.to_owned(), value));
+    }
+    Ok(vars)
+}
+
+/// The date in a `>=YYYY-MM-DD` version constraint.
+pub(crate) fn min_release_date(constraint: &str) -> Result<&str, Error> {
+    constraint
+        .strip_prefix(">=")
+        .map(str::trim)
+        .filter(|date| is_release_date(date))
+        .ok_or_else(|| {
+            anyhow!("unsupported buck2 version constraint '{constraint}', expected >=YYYY-MM-DD")
+        })
+}
+
+/// Whether `tag` is a date in buck2's `YYYY-MM-DD` release tag format.
+pub(crate) fn is_release_date(tag: &str) -> bool {
+    let bytes = tag.as_bytes();
+    bytes.len() == 10
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// A setting that must be a release date, if it is set.
+fn release_date_setting(name: &str, value: Option<String>) -> Result<Option<String>, Error> {
+    match value {
+        Some(date) if !is_release_date(&date) => Err(anyhow!(
+            "{name} must be a release date in the format YYYY-MM-DD, not '{date}'"
+        )),
+        value => Ok(value),
+    }
+}
+
+/// The effective configuration, after environment overrides and defaults are applied.
+///
+/// `--buckle-print-config` prints this as TOML.
+#[derive(Debug, Serialize)]
+pub struct BuckleConfig {
+    pub buck2_version: String,
+    pub base_download_url: String,
+    pub check_prelude: bool,
+    /// Refuse to run buck2 when the prelude doesn't match, rather than only warning.
+    pub prelude_check_strict: bool,
+    pub buckle_dir: PathBuf,
+    pub post_download_command: Option<Vec<String>>,
+    pub prelude_section: Option<String>,
+    /// Expected prelude hashes by cell name, for projects with more than one prelude. The
+    /// `prelude` cell is checked against the release's hash unless it has an entry here.
+    pub prelude_hashes: BTreeMap<String, String>,
+    pub stable_manifest_url: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    pub danger_accept_invalid_certs: bool,
+    pub user_agent: String,
+    /// How long `latest` stays pinned to the release it resolved to before checking for a newer
+    /// one. Unset means every time the releases list is refreshed.
+    pub cache_timeout_secs: Option<u64>,
+    /// Never look for a newer `latest` once one is downloaded, so cached runs stay offline.
+    pub disable_update_check: bool,
+    /// Resolve `latest` or a version constraint again, ignoring the pin and the cached releases
+    /// list. Set by `--buckle-reresolve` for this run only.
+    pub reresolve: bool,
+    /// Match releases by exact tag only, rather than also by name and ignoring a `v` prefix.
+    pub strict_version_match: bool,
+    /// Whether version constraints, and versions only found by release name, may resolve to a
+    /// release marked as a prerelease. Off by default; a release tagged exactly as asked for, like
+    /// buck2's own `latest`, is used whatever it's marked as.
+    pub include_prerelease: bool,
+    /// Whether `latest` and version constraints may resolve to a draft release, whose assets may
+    /// not all be uploaded yet.
+    pub include_draft: bool,
+    /// The oldest buck2 release, as a `YYYY-MM-DD` date, the project's configuration works with.
+    pub min_buck2_version: Option<String>,
+    /// The newest buck2 release, as a `YYYY-MM-DD` date, the project's configuration works with.
+    pub max_buck2_version: Option<String>,
+    /// The GitHub API to look buck2 releases up in, e.g. `https://ghe.example.com/api/v3`.
+    pub github_api_base: String,
+    /// Whether to also link each version's binary as `bin/buck2-<version>` under `buckle_dir`.
+    pub versioned_bin: bool,
+    /// Keep cached binaries zstd-compressed and unpack them for each run, to save disk space.
+    pub cache_compression: bool,
+    /// Give buck2 the cached binary's path as its program name, rather than `buck2`. Only has an
+    /// effect on unix.
+    pub real_arg0: bool,
+    /// The exact release asset to download instead of `buck2-<target>.zst`.
+    pub artifact_name: Option<String>,
+    /// Companion files to fetch into the version directory next to `buck2`, e.g. debug symbols.
+    pub extra_artifacts: Vec<String>,
+    /// A `SHA256SUMS`-style file in the release to check the archive and extra artifacts against.
+    pub checksum_manifest: Option<String>,
+    /// How long to wait for the releases API before using the cached releases list.
+    pub releases_api_timeout_secs: u64,
+    /// How many releases to ask the releases API for per page, at most 100.
+    pub releases_per_page: u32,
+    /// How long to keep checking for the archive of a release whose assets aren't uploaded yet.
+    pub wait_for_release_secs: Option<u64>,
+    /// The most bytes the buck2 archive may decompress to. Unset means no limit.
+    pub max_decompressed_bytes: Option<u64>,
+    /// Extra environment variables for buck2, on top of the inherited ones.
+    pub env: BTreeMap<String, String>,
+    /// A dotenv-style file with more variables for buck2, which `env` takes precedence over. A
+    /// relative path in `.buckleconfig.toml` is relative to that file's directory.
+    pub env_file: Option<PathBuf>,
+    /// Arguments passed to buck2 ahead of the user's own on every invocation.
+    pub default_args: Vec<String>,
+    /// A command to run buck2 under, e.g. `["nice", "-n", "10"]`, which gets buck2's path and
+    /// arguments appended. Empty means buck2 is run directly.
+    pub exec_wrapper: Vec<String>,
+    /// Inherited environment variables to hide from buck2. A `*` matches any run of characters.
+    pub env_remove: Vec<String>,
+    /// Target triples to download for `<arch>-<os>` hosts, e.g. `x86_64-linux`, in place of the
+    /// built-in ones.
+    pub targets: BTreeMap<String, String>,
+    /// The only hosts buckle may connect to, from `BUCKLE_ALLOWED_HOSTS`. Unset allows any.
+    pub allowed_hosts: Option<Vec<String>>,
+    /// Where downloads are unpacked before being moved into the cache, from `BUCKLE_TMPDIR`.
+    /// Unset means the version directory itself.
+    pub tmp_dir: Option<PathBuf>,
+    /// The `.buckleconfig.toml` the file settings came from, if any.
+    pub config_file: Option<PathBuf>,
+}
+
+/// What buckle uses when nothing is configured, with the cache in the OS cache directory. Without
+/// one, `buckle_dir` is left empty and resolving fails with `BuckleError::NoCacheDir`.
+impl Default for BuckleConfig {
+    fn default() -> BuckleConfig {
+        BuckleConfig {
+            buck2_version: String::from("latest"),
+            base_download_url: UPSTREAM_BASE_URL.to_owned(),
+            check_prelude: true,
+            prelude_check_strict: false,
+            buckle_dir: get_os_cache_dir()
+                .map(|dir| dir.join("buckle"))
+                .unwrap_or_default(),
+            post_download_command: None,
+            prelude_section: None,
+            prelude_hashes: BTreeMap::new(),
+            stable_manifest_url: None,
+            ca_bundle: None,
+            danger_accept_invalid_certs: false,
+            user_agent: format!("buckle/{}", env!("CARGO_PKG_VERSION")),
+            cache_timeout_secs: None,
+            disable_update_check: false,
+            reresolve: false,
+            strict_version_match: false,
+            include_prerelease: false,
+            include_draft: false,
+            min_buck2_version: None,
+            max_buck2_version: None,
+            github_api_base: GITHUB_API_BASE.to_owned(),
+            versioned_bin: false,
+            cache_compression: false,
+            real_arg0: false,
+            artifact_name: None,
+            extra_artifacts: vec![],
+            checksum_manifest: None,
+            releases_api_timeout_secs: 5,
+            releases_per_page: 100,
+            wait_for_release_secs: None,
+            max_decompressed_bytes: None,
+            env: BTreeMap::new(),
+            env_file: None,
+            default_args: vec![],
+            exec_wrapper: vec![],
+            env_remove: vec![],
+            targets: BTreeMap::new(),
+            allowed_hosts: None,
+            tmp_dir: None,
+            config_file: None,
+        }
+    }
+}
+
+/// Read the configuration the `buckle` binary runs with, from environment variables, the nearest
+/// `.buckleconfig.toml` and `.buckle-version`, and defaults.
+pub(crate) fn read_config() -> Result<BuckleConfig, Error> {
+    load_config().map_err(|e| match e.downcast::<BuckleError>() {
+        Ok(e) => e.into(),
+        Err(e) => BuckleError::ConfigInvalid {
+            reason: e.to_string(),
+        }
+        .into(),
+    })
+}
+
+fn load_config() -> Result<BuckleConfig, Error> {
+    #[derive(Default, Deserialize)]
+    struct BuckleFileConfig {
+        buck2_version: Option<String>,
+        base_download_url: Option<String>,
+        check_prelude: Option<bool>,
+        prelude_check_strict: Option<bool>,
+        cache_dir: Option<PathBuf>,
+        post_download_command: Option<Vec<String>>,
+        prelude_section: Option<String>,
+        stable_manifest_url: Option<String>,
+        ca_bundle: Option<PathBuf>,
+        cache_timeout_secs: Option<u64>,
+        disable_update_check: Option<bool>,
+        strict_version_match: Option<bool>,
+        include_prerelease: Option<bool>,
+        include_draft: Option<bool>,
+        min_buck2_version: Option<String>,
+        max_buck2_version: Option<String>,
+        env_remove: Option<Vec<String>>,
+        env_file: Option<PathBuf>,
+        targets: Option<BTreeMap<String, String>>,
+        default_args: Option<Vec<String>>,
+        exec_wrapper: Option<Vec<String>>,
+        releases_api_timeout_secs: Option<u64>,
+        // Wide enough that any out of range number gets the error below rather than a parse one.
+        releases_per_page: Option<i64>,
+        extra_artifacts: Option<Vec<String>>,
+        checksum_manifest: Option<String>,
+        artifact_name: Option<String>,
+        versioned_bin: Option<bool>,
+        cache_compression: Option<bool>,
+        real_arg0: Option<bool>,
+        github_api_base: Option<String>,
+        max_decompressed_bytes: Option<u64>,
+    }
+
+    // The config crate lowercases keys, which environment variable and cell names can't afford,
+    // so the `[env]` and `[prelude_hashes]` tables are read on their own.
+    #[derive(Default, Deserialize)]
+    struct BuckleTablesConfig {
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+        #[serde(default)]
+        prelude_hashes: BTreeMap<String, String>,
+    }
+
+    let (config_file, file_config) = (|| -> Result<(Option<PathBuf>, BuckleFileConfig), Error> {
+        for dir in working_dir()?.ancestors() {
+            let config_file = dir.join(".buckleconfig.toml");
+            if config_file.exists() {
+                let file_config = config::Config::builder()
+                    .add_source(config::File::from(config_file.clone()))
+                    .build()?
+                    .try_deserialize::<BuckleFileConfig>()?;
+                return Ok((Some(config_file), file_config));
+            }
+        }
+        Ok((None, BuckleFileConfig::default()))
+    })()?;
+
+    let BuckleTablesConfig {
+        env,
+        prelude_hashes,
+    } = match &config_file {
+        Some(path) => {
+            toml::from_str::<BuckleTablesConfig>(&fs::read_to_string(path)?).map_err(|e| {
+                anyhow!(
+                    "invalid [env] or [prelude_hashes] table in {}: {e}",
+                    path.display()
+                )
+            })?
+        }
+        None => BuckleTablesConfig::default(),
+    };
+
+    let buck2_version = if let Ok(version) = env::var("USE_BUCK2_VERSION") {
+        version
+    } else if let Some(version) =
+        nearest_buck2_version(config_file.as_deref(), file_config.buck2_version.as_deref())?
+    {
+        version
+    } else if let Some(root) = get_buck2_project_root()? {
+        let root: PathBuf = [root, Path::new(".buckversion")].iter().collect();
+        if root.exists() {
+            warn!("reading Buck2 version from deprecated {root:?}, please use a .buckleconfig.toml file instead");
+            fs::read_to_string(root)?.trim().to_string()
+        } else {
+            String::from("latest")
+        }
+    } else {
+        String::from("latest")
+    };
+
+    let base_download_url = if let Ok(url) = env::var("BUCKLE_DOWNLOAD_URL") {
+        url
+    } else if let Some(url) = file_config.base_download_url {
+        url.clone()
+    } else {
+        UPSTREAM_BASE_URL.to_owned()
+    };
+
+    let check_prelude =
+        if let Ok(check) = env::var("BUCKLE_PRELUDE_CHECK").map(|var| var.to_uppercase() != "NO") {
+            check
+        } else {
+            file_config.check_prelude.unwrap_or(true)
+        };
+    // Without a prelude hash there is nothing to check against.
+    let check_prelude = check_prelude
+        && !env::var("BUCKLE_NO_PRELUDE_FETCH")
+            .is_ok_and(|var| var == "1" || var.to_uppercase() == "YES");
+
+    let cache_compression = env::var("BUCKLE_CACHE_COMPRESSION")
+        .map(|var| var == "1" || var.to_uppercase() == "YES")
+        .ok()
+        .or(file_config.cache_compression)
+        .unwrap_or(false);
+    let versioned_bin = file_config.versioned_bin.unwrap_or(false);
+    if versioned_bin && cache_compression {
+        return Err(anyhow!(
+            "versioned_bin and cache_compression can't be used together: a compressed version has no plain buck2 to put in bin/"
+        ));
+    }
+
+    // `BUCKLE_CACHE`, `cache_dir` and the OS default name a shared cache directory that buckle
+    // keeps a `buckle` directory in, while `BUCKLE_HOME` is that directory itself.
+    let buckle_dir = if let Ok(cache_dir) = env::var("BUCKLE_CACHE") {
+        PathBuf::from(cache_dir).join("buckle")
+    } else if let Some(home) = env::var_os("BUCKLE_HOME").filter(|home| !home.is_empty()) {
+        PathBuf::from(home)
+    } else if let Some(cache_dir) = file_config.cache_dir {
+        cache_dir.join("buckle")
+    } else {
+        get_os_cache_dir()?.join("buckle")
+    };
+    if buckle_dir.starts_with("/mnt") && host_is_wsl() {
+        warn!(
+            "the buckle cache {} is on a Windows drive, which is slow under WSL and doesn't keep executable permissions. Set BUCKLE_CACHE to a directory on the Linux filesystem instead.",
+            buckle_dir.display()
+        );
+    }
+
+    Ok(BuckleConfig {
+        buck2_version,
+        base_download_url,
+        check_prelude,
+        prelude_check_strict: env::var("BUCKLE_PRELUDE_STRICT")
+            .map(|var| var == "1" || var.to_uppercase() == "YES")
+            .ok()
+            .or(file_config.prelude_check_strict)
+            .unwrap_or(false),
+        buckle_dir,
+        post_download_command: file_config.post_download_command,
+        prelude_section: file_config.prelude_section,
+        prelude_hashes,
+        stable_manifest_url: env::var("BUCKLE_STABLE_MANIFEST_URL")
+            .ok()
+            .or(file_config.stable_manifest_url),
+        ca_bundle: env::var_os("BUCKLE_CA_BUNDLE")
+            .map(PathBuf::from)
+            .or(file_config.ca_bundle),
+        // Like `allowed_hosts`, not settable from `.buckleconfig.toml`, so a checked-in config
+        // can't turn verification off for everyone.
+        danger_accept_invalid_certs: env::var("BUCKLE_DANGER_ACCEPT_INVALID_CERTS")
+            .is_ok_and(|var| var == "1" || var.to_uppercase() == "YES"),
+        user_agent: env::var("BUCKLE_USER_AGENT")
+            .unwrap_or_else(|_| format!("buckle/{}", env!("CARGO_PKG_VERSION"))),
+        cache_timeout_secs: file_config.cache_timeout_secs,
+        disable_update_check: env::var("BUCKLE_DISABLE_UPDATE_CHECK")
+            .map(|var| var == "1" || var.to_uppercase() == "YES")
+            .ok()
+            .or(file_config.disable_update_check)
+            .unwrap_or(false),
+        reresolve: false,
+        strict_version_match: file_config.strict_version_match.unwrap_or(false),
+        include_prerelease: file_config.include_prerelease.unwrap_or(false),
+        include_draft: file_config.include_draft.unwrap_or(false),
+        min_buck2_version: release_date_setting(
+            "min_buck2_version",
+            file_config.min_buck2_version,
+        )?,
+        max_buck2_version: release_date_setting(
+            "max_buck2_version",
+            file_config.max_buck2_version,
+        )?,
+        github_api_base: env::var("BUCKLE_GITHUB_API")
+            .ok()
+            .or(file_config.github_api_base)
+            .unwrap_or_else(|| GITHUB_API_BASE.to_owned()),
+        versioned_bin,
+        cache_compression,
+        real_arg0: env::var("BUCKLE_REAL_ARG0")
+            .map(|var| var == "1" || var.to_uppercase() == "YES")
+            .ok()
+            .or(file_config.real_arg0)
+            .unwrap_or(false),
+        artifact_name: file_config.artifact_name,
+        extra_artifacts: file_config.extra_artifacts.unwrap_or_default(),
+        checksum_manifest: file_config.checksum_manifest,
+        releases_api_timeout_secs: match env::var("BUCKLE_RELEASES_API_TIMEOUT") {
+            Ok(secs) => secs.parse().map_err(|_| {
+                anyhow!("BUCKLE_RELEASES_API_TIMEOUT must be a number of seconds, not '{secs}'")
+            })?,
+            Err(_) => file_config.releases_api_timeout_secs.unwrap_or(5),
+        },
+        releases_per_page: match file_config.releases_per_page.unwrap_or(100) {
+            per_page @ 1..=100 => per_page as u32,
+            per_page => {
+                return Err(anyhow!(
+                    "releases_per_page must be between 1 and 100, not {per_page}"
+                ))
+            }
+        },
+        wait_for_release_secs: match env::var("BUCKLE_WAIT_FOR_RELEASE") {
+            Ok(secs) => Some(secs.parse().map_err(|_| {
+                anyhow!("BUCKLE_WAIT_FOR_RELEASE must be a number of seconds, not '{secs}'")
+            })?),
+            Err(_) => None,
+        },
+        max_decompressed_bytes: file_config.max_decompressed_bytes,
+        env,
+        default_args: file_config.default_args.unwrap_or_default(),
+        exec_wrapper: file_config.exec_wrapper.unwrap_or_default(),
+        env_remove: file_config.env_remove.unwrap_or_default(),
+        env_file: file_config.env_file.map(|path| {
+            match config_file.as_deref().and_then(Path::parent) {
+                Some(dir) => dir.join(path),
+                None => path,
+            }
+        }),
+        targets: file_config.targets.unwrap_or_default(),
+        // Deliberately not settable from `.buckleconfig.toml`, which the list guards against.
+        allowed_hosts: env::var("BUCKLE_ALLOWED_HOSTS").ok().map(|hosts| {
+            hosts
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(str::to_owned)
+                .collect()
+        }),
+        tmp_dir: env::var_os("BUCKLE_TMPDIR")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from),
+        config_file,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_root_cache_warning() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let buckle_dir = dir.path().join("buckle");
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        assert_eq!(root_cache_warning(1000, &buckle_dir), None);
+        let owner = fs::metadata(dir.path()).unwrap().uid();
+        assert_eq!(root_cache_warning(0, &buckle_dir).is_some(), owner != 0);
+
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o777)).unwrap();
+        assert_eq!(root_cache_warning(1000, &buckle_dir), None);
+        let warning = root_cache_warning(0, &buckle_dir).unwrap();
+        assert!(warning.contains("BUCKLE_CACHE"), "found {warning}");
+    }
+
+    #[test]
+    fn test_parse_env_file() {
+        let vars = parse_env_file(
+            "# buck2 settings\n\
+             \n\
+             PLAIN=value # a comment\n\
+             export EXPORTED = spaced out\n\
+             SINGLE='no $expansion # here'\n\
+             DOUBLE=\"line\\none \\\"quoted\\\"\"\n\
+             EMPTY=\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vars,
+            [
+                ("PLAIN", "value"),
+                ("EXPORTED", "spaced out"),
+                ("SINGLE", "no $expansion # here"),
+                ("DOUBLE", "line\none \"quoted\""),
+                ("EMPTY", ""),
+            ]
+            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        );
+
+        let e = parse_env_file("OK=1\nnot a variable\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: expected KEY=value");
+        assert!(parse_env_file("OPEN=\"never closed\n").is_err());
+    }
+
+    fn env_of<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        move |name| {
+            vars.iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.to_string())
+        }
+    }
+
+    #[test]
+    fn test_termux_cache_dir() {
+        let termux = [
+            ("HOME", "/data/data/com.termux/files/home"),
+            ("PREFIX", "/data/data/com.termux/files/usr"),
+            ("TERMUX_VERSION", "0.118.0"),
+        ];
+        assert!(is_termux(env_of(&termux)));
+        assert_eq!(
+            termux_cache_dir(env_of(&termux)).unwrap(),
+            Path::new("/data/data/com.termux/files/usr/var/cache")
+        );
+
+        // Older Termux releases don't set TERMUX_VERSION, but the prefix gives it away.
+        assert_eq!(
+            termux_cache_dir(env_of(&termux[..2])).unwrap(),
+            Path::new("/data/data/com.termux/files/usr/var/cache")
+        );
+
+        // An explicit XDG cache still wins.
+        let xdg = [termux[1], ("XDG_CACHE_HOME", "/sdcard/cache")];
+        assert_eq!(termux_cache_dir(env_of(&xdg)), None);
+    }
+
+    #[test]
+    fn test_termux_cache_dir_elsewhere() {
+        // A PREFIX outside Termux (e.g. from a build script) is not a cache location.
+        let linux = [("HOME", "/home/me"), ("PREFIX", "/usr/local")];
+        assert!(!is_termux(env_of(&linux)));
+        assert_eq!(termux_cache_dir(env_of(&linux)), None);
+        assert_eq!(termux_cache_dir(env_of(&[])), None);
+    }
+
+    #[test]
+    fn test_wsl_cache_dir() {
+        assert!(is_wsl(
+            "Linux version 5.15.153.1-microsoft-standard-WSL2 (root@941d701f84f1) (gcc (GCC) 11.2.0)"
+        ));
+        assert!(!is_wsl(
+            "Linux version 6.8.0-45-generic (buildd@lcy02-amd64-075)"
+        ));
+
+        let windows_home = [("HOME", "/mnt/c/Users/me"), ("USER", "me")];
+        assert_eq!(
+            wsl_cache_dir(env_of(&windows_home), |_| true).unwrap(),
+            Path::new("/home/me/.cache")
+        );
+        // Without a Linux home directory there is nowhere better to go.
+        assert_eq!(wsl_cache_dir(env_of(&windows_home), |_| false), None);
+        // A Linux home, or an explicit XDG cache, is left alone.
+        let linux_home = [("HOME", "/home/me"), ("USER", "me")];
+        assert_eq!(wsl_cache_dir(env_of(&linux_home), |_| true), None);
+        let xdg = [
+            windows_home[0],
+            windows_home[1],
+            ("XDG_CACHE_HOME", "/mnt/d/cache"),
+        ];
+        assert_eq!(wsl_cache_dir(env_of(&xdg), |_| true), None);
+    }
+
+    #[test]
+    fn test_min_release_date() {
+        assert_eq!(min_release_date(">=2023-07-01").unwrap(), "2023-07-01");
+        assert_eq!(min_release_date(">= 2023-07-01").unwrap(), "2023-07-01");
+        assert!(min_release_date("<=2023-07-01").is_err());
+        assert!(min_release_date(">=v7.0.0").is_err());
+        assert!(min_release_date(">=2023-7-1").is_err());
+    }
+}
@@ -0,0 +1,1084 @@
+//! Downloading buck2 into the cache and keeping the cache in shape.
+
+#[cfg(unix)]
+use crate::config::root_cache_warning;
+use crate::config::{set_mtime, source_date_epoch, write_atomically, BuckleConfig};
+use crate::error::BuckleError;
+use crate::http::{
+    check_download_response, fetch_resumable, http_client, send, transfer_summary, HttpClient,
+    LimitedWriter,
+};
+use crate::platform::get_arch;
+use crate::prelude::PreludeMetadata;
+use crate::releases::{find_release, refresh_releases, BUCK_RELEASE_URL};
+use crate::resolve::{check_version_window, is_exact_version, resolve_version, ResolvedVersion};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::{
+    ffi::OsString,
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::{NamedTempFile, TempPath};
+
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::time::SystemTime;
+
+/// A version directory that is ready to use.
+pub struct Installed {
+    /// The version directory holding `buck2` and `prelude_hash`.
+    pub dir: PathBuf,
+    /// The release it was downloaded from.
+    pub tag: String,
+    /// Whether it was already cached rather than downloaded just now.
+    pub cache_hit: bool,
+}
+
+impl Installed {
+    /// Whether the binary is only kept as `buck2.zst`, as downloaded with `cache_compression`.
+    pub fn is_compressed(&self) -> bool {
+        !self.dir.join(BINARY).exists() && self.dir.join(COMPRESSED_BINARY).exists()
+    }
+
+    /// The cached binary: `buck2`, or `buck2.zst` for a compressed version, which has to be
+    /// unpacked before it can run.
+    pub fn binary(&self) -> PathBuf {
+        if self.is_compressed() {
+            self.dir.join(COMPRESSED_BINARY)
+        } else {
+            self.dir.join(BINARY)
+        }
+    }
+}
+
+/// Download the configured version into `output_dir`, unless it is there already.
+pub(crate) fn download_http(config: &BuckleConfig, output_dir: &Path) -> Result<Installed, Error> {
+    let resolved = resolve_version(config, output_dir)?;
+    check_version_window(config, &resolved)?;
+    let ResolvedVersion {
+        tag: version,
+        dir,
+        commit,
+        ..
+    } = resolved;
+    let mut buck2_path = dir;
+
+    // Path to directory that caches buck
+    let dir_path = buck2_path.clone();
+
+    // Only one process may populate a version directory at a time. Anyone else waits here and then
+    // finds it complete. The lock is released when `_lock` is dropped, whichever way we return.
+    let _lock = lock_version_dir(&dir_path)?;
+    let arch = get_arch(&config.targets)?;
+    if dir_path.exists() {
+        // A cache shared between machines, e.g. on an NFS home directory, can hold a binary built
+        // for another target. Replace it rather than failing to exec it later. Likewise a version
+        // directory that isn't the commit the version resolved to has been tampered with or
+        // mixed up, which the recorded source shows without running buck2.
+        let source = Source::read(&dir_path).ok();
+        let stale = match source {
+            Some(Source {
+                target: Some(target),
+                ..
+            }) if target != arch => Some(format!("was downloaded for {target}, not {arch}")),
+            Some(Source {
+                commit: Some(cached),
+                ..
+            }) if commit.as_ref().is_some_and(|commit| *commit != cached) => Some(format!(
+                "is commit {cached}, not {}",
+                commit.as_deref().unwrap_or_default()
+            )),
+            _ => None,
+        };
+        match stale {
+            Some(why) => {
+                warn!("the cached buck2 {version} {why}; downloading it again");
+                fs::remove_dir_all(&dir_path)?;
+            }
+            // Already downloaded
+            None => {
+                // Record the use for `--buckle-gc`, since access times are often not kept.
+                if source_date_epoch().is_none() {
+                    let _ = set_mtime(&dir_path, SystemTime::now());
+                }
+                return Ok(Installed {
+                    dir: dir_path,
+                    tag: version,
+                    cache_hit: true,
+                });
+            }
+        }
+    }
+
+    let client = http_client(config)?;
+    let base_url = &config.base_download_url;
+
+    // Fetch the compressed archive next to the version directory first, so an interrupted download
+    // can be resumed on the next run without the version directory looking populated. Like the
+    // unpacked binary, it goes under `BUCKLE_TMPDIR` instead when that is set.
+    let mut part_name = dir_path.file_name().unwrap_or_default().to_owned();
+    part_name.push(".zst.part");
+    let part_path = config
+        .tmp_dir
+        .as_deref()
+        .unwrap_or(output_dir)
+        .join(part_name);
+    info!("fetching buck2 {version}");
+    let started = std::time::Instant::now();
+    // Only a pinned release can be waited for; anything else resolved to one that was published.
+    let wait = config
+        .wait_for_release_secs
+        .filter(|_| is_exact_version(&config.buck2_version));
+    let url = match &config.artifact_name {
+        Some(name) => {
+            // While the assets are still being uploaded, the release not listing this one yet
+            // is expected, so it's left to the wait to give up.
+            if wait.is_none() {
+                check_release_has_asset(config, output_dir, &version, name)?;
+            }
+            format!("{base_url}/{version}/{name}")
+        }
+        None => format!("{base_url}/{version}/buck2-{arch}.zst"),
+    };
+    let fetched = match wait {
+        Some(wait) => fetch_when_uploaded(&client, &url, &part_path, &version, wait, || {
+            // Keep the releases list up to date with the release being published. A mirror
+            // without one is fine.
+            let _ = refresh_releases(config, output_dir);
+        })?,
+        None => fetch_resumable(&client, &url, &part_path)?,
+    };
+    info!(
+        "fetched buck2 ({})",
+        transfer_summary(fetched, started.elapsed())
+    );
+    // One manifest covers the archive and every extra artifact, so it is fetched once.
+    let manifest = match &config.checksum_manifest {
+        Some(pattern) => {
+            let manifest = fetch_checksum_manifest(&client, base_url, &version, &arch, pattern)?;
+            let name = url.rsplit('/').next().unwrap_or_default();
+            if let Err(e) = manifest.verify(name, || Checksum::sha256_of(&part_path)) {
+                // Resuming from a corrupt archive would only fail again.
+                let _ = fs::remove_file(&part_path);
+                return Err(e);
+            }
+            Some(manifest)
+        }
+        None => None,
+    };
+
+    buck2_path.push(BINARY);
+    fs::create_dir_all(&dir_path)?;
+    // A half-filled version directory would pass for a cache hit next time, so remove it again if
+    // anything goes wrong from here on.
+    let populated = (|| -> Result<(), Error> {
+        // Decode the archive and make it executable
+        let mut tmp_buck2_bin =
+            NamedTempFile::new_in(config.tmp_dir.as_deref().unwrap_or(&dir_path))?;
+        let mut limited = LimitedWriter {
+            inner: &mut tmp_buck2_bin,
+            remaining: config.max_decompressed_bytes,
+            exceeded: false,
+        };
+        if let Err(e) = zstd::stream::copy_decode(File::open(&part_path)?, &mut limited) {
+            // The archive itself is bad; don't try to resume from it.
+            let _ = fs::remove_file(&part_path);
+            if limited.exceeded {
+                return Err(anyhow!(
+                    "the buck2 archive decompresses to more than max_decompressed_bytes ({} bytes), refusing to unpack it",
+                    config.max_decompressed_bytes.unwrap_or_default()
+                ));
+            }
+            return Err(anyhow!("failed to decompress the buck2 archive: {e}"));
+        }
+        tmp_buck2_bin.flush()?;
+        #[cfg(unix)]
+        {
+            let permissions = fs::Permissions::from_mode(0o755);
+            fs::set_permissions(&tmp_buck2_bin, permissions)?;
+        }
+        // Close our handle, so the hook below can execute the file.
+        let unpacked = tmp_buck2_bin.into_temp_path();
+        let checksum = Checksum::sha256_of(&unpacked)?;
+        // The binary the hook is shown. A compressed cache has no plain `buck2`, so it gets the
+        // unpacked copy, which is the same binary every run unpacks.
+        let hook_binary = if config.cache_compression {
+            // Keep the archive itself; it is unpacked again for every run.
+            move_file(&part_path, &dir_path.join(COMPRESSED_BINARY))?;
+            unpacked.to_path_buf()
+        } else {
+            move_file(&unpacked, &buck2_path)?;
+            buck2_path.clone()
+        };
+        checksum.write(&dir_path)?;
+        Source {
+            url: url.clone(),
+            tag: version.clone(),
+            downloaded_at: source_date_epoch()
+                .unwrap_or_else(SystemTime::now)
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+            checksum: Some(checksum),
+            target: Some(arch.clone()),
+            commit: commit.clone(),
+        }
+        .write(&dir_path)?;
+        if !config.cache_compression {
+            fs::remove_file(&part_path)?;
+        }
+
+        // Also fetch the prelude hash and store it. Only the prelude check needs it, and some
+        // buck2 forks don't publish one.
+        if config.check_prelude {
+            let mut prelude_path = dir_path.clone();
+            prelude_path.push(PRELUDE_HASH_FILE);
+            let url = format!("{base_url}/{version}/{PRELUDE_HASH_FILE}");
+            let resp = check_download_response(&url, send(client.get(&url)?)?)?;
+            let hash = resp.bytes()?;
+            let mut prelude_hash = File::create(prelude_path)?;
+            prelude_hash.write_all(&hash)?;
+            prelude_hash.flush()?;
+            PreludeMetadata {
+                hash: String::from_utf8_lossy(&hash).trim().to_owned(),
+                version: version.clone(),
+                base_download_url: base_url.clone(),
+            }
+            .write(&dir_path)?;
+        }
+
+        for pattern in &config.extra_artifacts {
+            fetch_extra_artifact(
+                &client,
+                base_url,
+                &version,
+                &arch,
+                pattern,
+                &dir_path,
+                manifest.as_ref(),
+            )?;
+        }
+
+        // Only a complete download counts as fresh, so the hook sees every new binary exactly
+        // once. If it fails, the version directory goes so the next run doesn't execute an
+        // unvetted binary.
+        if let Some(hook) = &config.post_download_command {
+            run_post_download_command(hook, &hook_binary)?;
+        }
+
+        if let Some(epoch) = source_date_epoch() {
+            for entry in fs::read_dir(&dir_path)? {
+                set_mtime(&entry?.path(), epoch)?;
+            }
+            set_mtime(&dir_path, epoch)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = populated {
+        let _ = fs::remove_dir_all(&dir_path);
+        return Err(e);
+    }
+
+    Ok(Installed {
+        dir: dir_path,
+        tag: version,
+        cache_hit: false,
+    })
+}
+
+/// Like `fetch_resumable`, but while the archive is missing keep checking for up to `wait_secs`,
+/// calling `refresh` before each new attempt.
+///
+/// A release pipeline may create the tag moments before its assets are uploaded.
+pub(crate) fn fetch_when_uploaded(
+    client: &HttpClient,
+    url: &str,
+    part_path: &Path,
+    version: &str,
+    wait_secs: u64,
+    mut refresh: impl FnMut(),
+) -> Result<u64, Error> {
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(wait_secs);
+    let mut delay = std::time::Duration::from_secs(1);
+    loop {
+        match fetch_resumable(client, url, part_path) {
+            Err(e)
+                if matches!(
+                    e.downcast_ref(),
+                    Some(BuckleError::DownloadFailed {
+                        status: Some(404),
+                        ..
+                    })
+                ) =>
+            {
+                let now = std::time::Instant::now();
+                if now >= deadline {
+                    return Err(anyhow!(
+                        "buck2 {version} was still not uploaded after waiting {wait_secs}s: {e}"
+                    ));
+                }
+                info!(
+                    "buck2 {version} is not uploaded yet, checking again in {}s",
+                    delay.as_secs()
+                );
+                std::thread::sleep(delay.min(deadline - now));
+                delay = (delay * 2).min(std::time::Duration::from_secs(30));
+                refresh();
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Make sure the release lists an asset called exactly `name`, and list the ones it has if not.
+///
+/// Mirrors may carry tags the releases API doesn't know, so a release that can't be looked up is
+/// left to the download itself to fail.
+pub(crate) fn check_release_has_asset(
+    config: &BuckleConfig,
+    output_dir: &Path,
+    version: &str,
+    name: &str,
+) -> Result<(), Error> {
+    let Ok(Some(release)) = find_release(config, output_dir, version) else {
+        return Ok(());
+    };
+    let names: Vec<&str> = release
+        .assets
+        .iter()
+        .filter_map(|asset| asset["name"].as_str())
+        .collect();
+    if names.contains(&name) {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "buck2 {} has no asset named {name}. It has: {}",
+        release.tag_name,
+        names.join(", ")
+    ))
+}
+
+/// A release's checksum manifest, like a `SHA256SUMS` file: `<sha256>  <file name>` lines, as
+/// `sha256sum` writes them.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ChecksumManifest {
+    /// The manifest's own URL, for error messages.
+    pub(crate) url: String,
+    pub(crate) digests: std::collections::HashMap<String, String>,
+}
+
+impl ChecksumManifest {
+    pub(crate) fn parse(url: &str, contents: &str) -> Result<ChecksumManifest, Error> {
+        let mut digests = std::collections::HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (digest, name) = line
+                .split_once(char::is_whitespace)
+                .filter(|(digest, _)| digest.len() == 64)
+                .filter(|(digest, _)| digest.chars().all(|c| c.is_ascii_hexdigit()))
+                .ok_or_else(|| anyhow!("invalid line in checksum manifest {url}: {line}"))?;
+            // `sha256sum --binary` marks names with a `*`.
+            let name = name.trim_start();
+            let name = name.strip_prefix('*').unwrap_or(name);
+            digests.insert(name.to_owned(), digest.to_ascii_lowercase());
+        }
+        Ok(ChecksumManifest {
+            url: url.to_owned(),
+            digests,
+        })
+    }
+
+    /// Check the checksum `actual` computes for `name` against the manifest. A file the manifest
+    /// doesn't list fails too, since it can't be vouched for.
+    pub(crate) fn verify(
+        &self,
+        name: &str,
+        actual: impl FnOnce() -> Result<Checksum, Error>,
+    ) -> Result<(), Error> {
+        let Some(expected) = self.digests.get(name) else {
+            return Err(anyhow!(
+                "the checksum manifest {} has no entry for {name}",
+                self.url
+            ));
+        };
+        let actual = actual()?.digest;
+        if *expected != actual {
+            return Err(anyhow!(
+                "{name} doesn't match the checksum manifest {}: expected sha256 {expected}, got {actual}",
+                self.url
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Fetch and parse the release's checksum manifest that `pattern` names. `{arch}` and `{version}`
+/// in it are filled in.
+pub(crate) fn fetch_checksum_manifest(
+    client: &HttpClient,
+    base_url: &str,
+    version: &str,
+    arch: &str,
+    pattern: &str,
+) -> Result<ChecksumManifest, Error> {
+    let name = pattern
+        .replace("{arch}", arch)
+        .replace("{version}", version);
+    let url = format!("{base_url}/{version}/{name}");
+    let resp = check_download_response(&url, send(client.get(&url)?)?)?;
+    ChecksumManifest::parse(&url, &resp.text()?)
+}
+
+/// Fetch the companion file `pattern` names from the release into the version directory. `{arch}`
+/// and `{version}` in it are filled in. These are nice to have, so a missing one is only a
+/// warning. One the checksum `manifest` lists must match it, though.
+pub(crate) fn fetch_extra_artifact(
+    client: &HttpClient,
+    base_url: &str,
+    version: &str,
+    arch: &str,
+    pattern: &str,
+    dir_path: &Path,
+    manifest: Option<&ChecksumManifest>,
+) -> Result<(), Error> {
+    let name = pattern
+        .replace("{arch}", arch)
+        .replace("{version}", version);
+    let file_name = Path::new(&name)
+        .file_name()
+        .ok_or_else(|| anyhow!("extra artifact '{pattern}' does not name a file"))?;
+    let reserved = [
+        BINARY,
+        COMPRESSED_BINARY,
+        PRELUDE_HASH_FILE,
+        PreludeMetadata::FILE,
+        Checksum::FILE,
+        Source::FILE,
+        CACHE_VERSION_FILE,
+    ]
+    .iter()
+    .any(|own| file_name == *own);
+    if reserved || file_name.to_string_lossy().ends_with(LOCK_SUFFIX) {
+        return Err(anyhow!(
+            "extra artifact '{pattern}' would overwrite buckle's own {}",
+            file_name.to_string_lossy()
+        ));
+    }
+    let url = format!("{base_url}/{version}/{name}");
+    let resp = match send(client.get(&url)?)
+        .map_err(Error::from)
+        .and_then(|resp| check_download_response(&url, resp))
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            warn!("skipping extra artifact {name}: {e}");
+            return Ok(());
+        }
+    };
+    let bytes = resp.bytes()?;
+    let listed_name = name.rsplit('/').next().unwrap_or(&name);
+    if let Some(manifest) = manifest.filter(|manifest| manifest.digests.contains_key(listed_name)) {
+        manifest.verify(listed_name, || Checksum::sha256_of_reader(&bytes[..]))?;
+    }
+    fs::write(dir_path.join(file_name), bytes)?;
+    Ok(())
+}
+
+/// The checksum of the binary as it was downloaded, stored as `checksum.json` in the version
+/// directory.
+///
+/// Later runs trust the cached binary without hashing it again, unless `BUCKLE_VERIFY_EACH_RUN`
+/// asks for it to be compared against this.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Checksum {
+    /// Always `sha256` for now; recorded so the algorithm can change without misreading old files.
+    pub(crate) algorithm: String,
+    pub(crate) digest: String,
+}
+
+impl Checksum {
+    pub(crate) const FILE: &'static str = "checksum.json";
+
+    pub(crate) fn sha256_of(path: &Path) -> Result<Checksum, Error> {
+        Self::sha256_of_reader(File::open(path)?)
+    }
+
+    pub(crate) fn sha256_of_reader(mut reader: impl std::io::Read) -> Result<Checksum, Error> {
+        use sha2::{Digest, Sha256};
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut reader, &mut hasher)?;
+        Ok(Checksum {
+            algorithm: "sha256".to_owned(),
+            digest: hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        })
+    }
+
+    pub(crate) fn read(dir: &Path) -> Option<Checksum> {
+        let buf = fs::read_to_string(dir.join(Self::FILE)).ok()?;
+        serde_json::from_str(&buf).ok()
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        fs::write(dir.join(Self::FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Move `from` to `to`, copying it when `fs::rename` can't because they are on different
+/// filesystems, as happens with container overlay filesystems and mounted cache directories.
+pub(crate) fn move_file(from: &Path, to: &Path) -> Result<(), Error> {
+    move_file_with(from, to, |from, to| fs::rename(from, to))
+}
+
+/// `move_file`, renaming with `rename`.
+fn move_file_with(
+    from: &Path,
+    to: &Path,
+    rename: impl FnOnce(&Path, &Path) -> std::io::Result<()>,
+) -> Result<(), Error> {
+    match rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => copy_into_place(from, to),
+        result => Ok(result?),
+    }
+}
+
+/// The fallback of `move_file`. The copy is made next to `to` and renamed over it, so `to` is never
+/// half-written, and keeps the permissions of `from`.
+fn copy_into_place(from: &Path, to: &Path) -> Result<(), Error> {
+    let dir = to
+        .parent()
+        .ok_or(anyhow!("{} has no parent directory", to.display()))?;
+    let copy = NamedTempFile::new_in(dir)?;
+    fs::copy(from, copy.path())?;
+    copy.persist(to)?;
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+/// The binary in a version directory.
+pub(crate) const BINARY: &str = "buck2";
+
+/// What the binary is stored as in a version directory downloaded with `cache_compression`.
+pub(crate) const COMPRESSED_BINARY: &str = "buck2.zst";
+
+/// The prelude hash a release publishes, kept in its version directory for the prelude check.
+pub(crate) const PRELUDE_HASH_FILE: &str = "prelude_hash";
+
+/// The marker under the cache directory recording its layout version.
+pub(crate) const CACHE_VERSION_FILE: &str = "CACHE_VERSION";
+
+/// What the lock file `lock_version_dir` takes next to a version directory adds to its name.
+pub(crate) const LOCK_SUFFIX: &str = ".lock";
+
+/// Unpack the compressed binary in `version_dir` for a single run. The file is removed again when
+/// the returned path is dropped.
+///
+/// It is unpacked into `version_dir` rather than the temporary directory, which is often mounted
+/// `noexec` on CI machines.
+pub(crate) fn unpack_compressed_binary(version_dir: &Path) -> Result<TempPath, Error> {
+    let compressed = version_dir.join(COMPRESSED_BINARY);
+    let mut unpacked = tempfile::Builder::new()
+        .prefix(".buck2-run")
+        .tempfile_in(version_dir)?;
+    zstd::stream::copy_decode(File::open(&compressed)?, &mut unpacked)
+        .map_err(|e| anyhow!("failed to decompress {}: {e}", compressed.display()))?;
+    unpacked.flush()?;
+    #[cfg(unix)]
+    fs::set_permissions(unpacked.path(), fs::Permissions::from_mode(0o755))?;
+    // Close our handle first, or executing the file fails with "text file busy".
+    Ok(unpacked.into_temp_path())
+}
+
+/// Where a cached buck2 came from, kept as `source.json` in its version directory for auditing.
+///
+/// `--buckle-audit` prints it for the active version.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Source {
+    /// The URL the compressed binary was downloaded from.
+    pub(crate) url: String,
+    /// The release tag it was downloaded as.
+    pub(crate) tag: String,
+    /// When it was downloaded, in seconds since the Unix epoch.
+    pub(crate) downloaded_at: u64,
+    /// The checksum of the unpacked binary, if one was computed.
+    pub(crate) checksum: Option<Checksum>,
+    /// The target triple it was downloaded for. Older caches didn't record one.
+    #[serde(default)]
+    pub(crate) target: Option<String>,
+    /// The commit the release was built from, if resolving the version looked it up.
+    #[serde(default)]
+    pub(crate) commit: Option<String>,
+}
+
+impl Source {
+    pub(crate) const FILE: &'static str = "source.json";
+
+    pub(crate) fn read(dir: &Path) -> Result<Source, Error> {
+        let path = dir.join(Self::FILE);
+        let buf = fs::read_to_string(&path).map_err(|e| {
+            anyhow!(
+                "no download source was recorded in {} ({e}). Remove {} to download it again.",
+                path.display(),
+                dir.display()
+            )
+        })?;
+        Ok(serde_json::from_str(&buf)?)
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        fs::write(dir.join(Self::FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// Hash the cached binary again and compare it with the checksum recorded when it was downloaded.
+pub(crate) fn verify_cached_binary(installed: &Installed, buck2_path: &Path) -> Result<(), Error> {
+    let Some(recorded) = Checksum::read(&installed.dir) else {
+        warn!(
+            "no checksum was recorded when {} was downloaded, so it can't be verified",
+            buck2_path.display()
+        );
+        return Ok(());
+    };
+    if recorded.algorithm != "sha256" {
+        return Err(anyhow!(
+            "{} uses the unsupported checksum algorithm '{}'",
+            installed.dir.join(Checksum::FILE).display(),
+            recorded.algorithm
+        ));
+    }
+    if Checksum::sha256_of(buck2_path)? != recorded {
+        return Err(BuckleError::CacheCorrupt {
+            path: installed.dir.clone(),
+            reason: format!(
+                "{} no longer matches the checksum recorded when it was downloaded",
+                buck2_path.display()
+            ),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Point `current` at the active version directory so tools can rely on a path that doesn't change
+/// when the version does.
+///
+/// On unix this is a relative symlink swapped in with a rename, so concurrent invocations never see
+/// it missing. Elsewhere the binary is copied, which is only redone when the version changes.
+pub(crate) fn update_current_link(current: &Path, version_dir: &Path) -> Result<(), Error> {
+    let target = version_dir
+        .file_name()
+        .ok_or(anyhow!("{} has no file name", version_dir.display()))?;
+
+    #[cfg(unix)]
+    {
+        if fs::read_link(current).is_ok_and(|existing| existing == Path::new(target)) {
+            return Ok(());
+        }
+        let mut tmp = current.as_os_str().to_owned();
+        tmp.push(format!(".tmp-{}", std::process::id()));
+        let tmp = PathBuf::from(tmp);
+        let _ = fs::remove_file(&tmp);
+        std::os::unix::fs::symlink(target, &tmp)?;
+        if let Err(e) = fs::rename(&tmp, current) {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let marker = current.join("source");
+        let source = target.to_string_lossy();
+        if fs::read_to_string(&marker).is_ok_and(|existing| existing == source) {
+            return Ok(());
+        }
+        if current.exists() {
+            fs::remove_dir_all(current)?;
+        }
+        fs::create_dir_all(current)?;
+        // A version cached with `cache_compression` only has its archive to copy.
+        let binary = if version_dir.join(BINARY).exists() {
+            BINARY
+        } else {
+            COMPRESSED_BINARY
+        };
+        fs::copy(version_dir.join(binary), current.join(binary))?;
+        fs::write(marker, source.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Make `version_dir`'s binary available as `bin/buck2-<version>` under `buckle_dir`, so several
+/// versions can sit in one directory on `PATH`. The link is a hard link where possible and a copy
+/// where not.
+pub(crate) fn update_versioned_bin(buckle_dir: &Path, version_dir: &Path) -> Result<(), Error> {
+    let version = version_dir
+        .file_name()
+        .ok_or(anyhow!("{} has no file name", version_dir.display()))?;
+    let source = version_dir.join(BINARY);
+    let bin_dir = buckle_dir.join("bin");
+    let mut name = OsString::from("buck2-");
+    name.push(version);
+    if cfg!(windows) {
+        name.push(".exe");
+    }
+    let link = bin_dir.join(name);
+    if link.exists() && same_file(&link, &source)? {
+        return Ok(());
+    }
+
+    fs::create_dir_all(&bin_dir)?;
+    let tmp = bin_dir.join(format!(".tmp-{}", std::process::id()));
+    let _ = fs::remove_file(&tmp);
+    if fs::hard_link(&source, &tmp).is_err() {
+        fs::copy(&source, &tmp)?;
+    }
+    if let Err(e) = fs::rename(&tmp, &link) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// Whether `a` and `b` are the same file. Without a way to tell, e.g. on Windows, they never are.
+pub(crate) fn same_file(a: &Path, b: &Path) -> Result<bool, Error> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let (a, b) = (fs::metadata(a)?, fs::metadata(b)?);
+        Ok(a.dev() == b.dev() && a.ino() == b.ino())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        Ok(false)
+    }
+}
+
+/// Take an exclusive lock guarding the download of `dir_path`, blocking until it is available.
+pub(crate) fn lock_version_dir(dir_path: &Path) -> Result<File, Error> {
+    use fs2::FileExt;
+
+    let lock_path = version_lock_path(dir_path);
+    if let Some(parent) = dir_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = File::create(&lock_path)?;
+    lock.lock_exclusive()
+        .map_err(|e| anyhow!("failed to lock {}: {e}", lock_path.display()))?;
+    Ok(lock)
+}
+
+/// The lock file `lock_version_dir` takes for `dir_path`, next to it.
+fn version_lock_path(dir_path: &Path) -> PathBuf {
+    let mut lock_path = dir_path.as_os_str().to_owned();
+    lock_path.push(LOCK_SUFFIX);
+    PathBuf::from(lock_path)
+}
+
+/// The version of the cache layout this buckle writes, recorded in `CACHE_VERSION` under the
+/// cache directory. Bump it along with a migration in `check_cache_layout` when the layout changes.
+pub(crate) const CACHE_LAYOUT_VERSION: u32 = 1;
+
+/// Bring the cache in `buckle_dir` up to `CACHE_LAYOUT_VERSION`, migrating it from whatever layout
+/// its `CACHE_VERSION` says. A cache without one predates the marker and is version 0.
+///
+/// A cache written by a newer buckle is left as it is, with a warning, rather than rewritten into
+/// a layout that buckle no longer expects. An older layout there is no migration for is an error
+/// suggesting the cache is removed.
+pub(crate) fn check_cache_layout(buckle_dir: &Path) -> Result<(), Error> {
+    let marker = buckle_dir.join(CACHE_VERSION_FILE);
+    let found = match fs::read_to_string(&marker) {
+        Ok(contents) => contents.trim().parse::<u32>().map_err(|_| {
+            anyhow!(
+                "{} should hold a cache layout version, not '{}'. Remove it to have it written again.",
+                marker.display(),
+                contents.trim()
+            )
+        })?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(anyhow!("failed to read {}: {e}", marker.display())),
+    };
+    match found {
+        // Version 0 is the layout buckle had before the marker existed, which is version 1, so
+        // only the marker needs writing.
+        0 => {}
+        CACHE_LAYOUT_VERSION => return Ok(()),
+        found if found > CACHE_LAYOUT_VERSION => {
+            warn!(
+                "the cache at {} has layout version {found}, newer than the {CACHE_LAYOUT_VERSION} this buckle understands; leaving it as it is. Upgrade buckle, or point BUCKLE_CACHE somewhere else.",
+                buckle_dir.display()
+            );
+            return Ok(());
+        }
+        // Migrations from older layouts go here as the layout changes.
+        found => {
+            return Err(BuckleError::CacheCorrupt {
+                path: buckle_dir.to_path_buf(),
+                reason: format!(
+                    "the cache has layout version {found}, which this buckle can't migrate to {CACHE_LAYOUT_VERSION}"
+                ),
+            }
+            .into())
+        }
+    }
+    write_atomically(&marker, format!("{CACHE_LAYOUT_VERSION}\n"))?;
+    if let Some(epoch) = source_date_epoch() {
+        set_mtime(&marker, epoch)?;
+    }
+    Ok(())
+}
+
+/// The version directory `current` points at, if any.
+pub(crate) fn current_version_dir(buckle_dir: &Path) -> Option<PathBuf> {
+    let current = buckle_dir.join("current");
+    #[cfg(unix)]
+    let target = fs::read_link(current).ok()?.into_os_string();
+    #[cfg(not(unix))]
+    let target = OsString::from(fs::read_to_string(current.join("source")).ok()?);
+    Some(buckle_dir.join(target))
+}
+
+/// Which cached versions `--buckle-gc` removes. Without either limit, every version but the
+/// current one goes.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct GcPolicy {
+    /// Remove versions last used longer ago than this.
+    pub(crate) older_than: Option<std::time::Duration>,
+    /// Then remove the least recently used versions until the cache is no larger than this.
+    pub(crate) max_size: Option<u64>,
+}
+
+/// A version directory in the cache: one holding a `buck2` binary, stored plain or compressed.
+pub(crate) struct CachedVersion {
+    pub(crate) dir: PathBuf,
+    /// The release tag from its `source.json`, or the directory name if none was recorded.
+    pub(crate) tag: String,
+    pub(crate) last_used: SystemTime,
+    pub(crate) size: u64,
+}
+
+fn dir_size(path: &Path) -> Result<u64, Error> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// The version directories in `buckle_dir`, least recently used first.
+///
+/// A version's last use is its directory's modification time, which every run that uses it
+/// refreshes.
+pub(crate) fn cached_versions(buckle_dir: &Path) -> Result<Vec<CachedVersion>, Error> {
+    let mut versions = vec![];
+    for entry in fs::read_dir(buckle_dir)? {
+        let dir = entry?.path();
+        // `current` is a copy of a version rather than a link on some platforms.
+        let is_version = fs::symlink_metadata(&dir)?.is_dir()
+            && dir.file_name().is_some_and(|name| name != "current")
+            && (dir.join(BINARY).exists() || dir.join(COMPRESSED_BINARY).exists());
+        if !is_version {
+            continue;
+        }
+        let tag = match Source::read(&dir) {
+            Ok(source) => source.tag,
+            Err(_) => dir
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        versions.push(CachedVersion {
+            tag,
+            last_used: fs::metadata(&dir)?.modified()?,
+            size: dir_size(&dir)?,
+            dir,
+        });
+    }
+    versions.sort_by_key(|version| version.last_used);
+    Ok(versions)
+}
+
+/// Remove a cached version directory, waiting for any download of it to finish first.
+pub(crate) fn remove_cached_version(dir: &Path) -> Result<(), Error> {
+    let _lock = lock_version_dir(dir)?;
+    fs::remove_dir_all(dir)?;
+    // The lock goes too while it's still held, so nothing is left of the version. Windows won't
+    // remove a file that is open, so there it stays behind.
+    let _ = fs::remove_file(version_lock_path(dir));
+    Ok(())
+}
+
+/// Evict cached versions from `buckle_dir` according to `policy`, never touching `keep`. Returns
+/// the removed version directories and how many bytes each took.
+pub(crate) fn gc(
+    buckle_dir: &Path,
+    keep: Option<&Path>,
+    policy: &GcPolicy,
+) -> Result<Vec<(PathBuf, u64)>, Error> {
+    let mut versions = cached_versions(buckle_dir)?;
+    versions.retain(|version| Some(version.dir.as_path()) != keep);
+
+    let evict = match (policy.older_than, policy.max_size) {
+        (None, None) => versions.len(),
+        (older_than, max_size) => {
+            let cutoff = older_than.and_then(|age| SystemTime::now().checked_sub(age));
+            let kept_size = match keep {
+                Some(keep) if keep.exists() => dir_size(keep)?,
+                _ => 0,
+            };
+            let mut total = kept_size + versions.iter().map(|version| version.size).sum::<u64>();
+            let mut evict = 0;
+            for version in &versions {
+                let stale = cutoff.is_some_and(|cutoff| version.last_used < cutoff);
+                let too_big = max_size.is_some_and(|max_size| total > max_size);
+                if !stale && !too_big {
+                    break;
+                }
+                total -= version.size;
+                evict += 1;
+            }
+            evict
+        }
+    };
+
+    let mut removed = vec![];
+    for version in versions.into_iter().take(evict) {
+        remove_cached_version(&version.dir)?;
+        removed.push((version.dir, version.size));
+    }
+    Ok(removed)
+}
+
+/// Run the configured post-download hook with the path of the freshly downloaded binary.
+pub(crate) fn run_post_download_command(hook: &[String], buck2_path: &Path) -> Result<(), Error> {
+    let (program, args) = hook
+        .split_first()
+        .ok_or(anyhow!("post_download_command must not be empty"))?;
+    let status = Command::new(program)
+        .args(args)
+        .arg(buck2_path)
+        .status()
+        .map_err(|e| anyhow!("failed to run post_download_command '{program}': {e}"))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "post_download_command '{program}' failed with {status}"
+        ));
+    }
+    Ok(())
+}
+
+pub(crate) fn get_buck2_dir(config: &BuckleConfig) -> Result<Installed, Error> {
+    let buckle_dir = &config.buckle_dir;
+    #[cfg(unix)]
+    // SAFETY: geteuid has no preconditions and can't fail.
+    if let Some(warning) = root_cache_warning(unsafe { libc::geteuid() }, buckle_dir) {
+        warn!("{warning}");
+    }
+    if !buckle_dir.exists() {
+        fs::create_dir_all(buckle_dir)?;
+    }
+    check_cache_layout(buckle_dir)?;
+
+    let failed = |e: &dyn std::fmt::Display| {
+        format!(
+            "Failed to download release {} ({e}). Please check that this release exists at '{BUCK_RELEASE_URL}'.",
+            config.buck2_version,
+        )
+    };
+    // Keep what kind of failure it was for library callers.
+    download_http(config, buckle_dir).map_err(|e| match e.downcast::<BuckleError>() {
+        Ok(BuckleError::DownloadFailed {
+            url,
+            status,
+            reason,
+        }) => BuckleError::DownloadFailed {
+            url,
+            status,
+            reason: failed(&reason),
+        }
+        .into(),
+        Ok(e) => e.into(),
+        Err(e) => anyhow!(failed(&e)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_checksum_manifest() {
+        let a = "a".repeat(64);
+        let b = "B".repeat(64);
+        let manifest = ChecksumManifest::parse(
+            "SUMS",
+            &format!("# sums\n{a}  buck2.zst\n\n{b} *buck2.debuginfo\n"),
+        )
+        .unwrap();
+        assert_eq!(manifest.digests.len(), 2);
+        assert_eq!(manifest.digests["buck2.zst"], a);
+        assert_eq!(manifest.digests["buck2.debuginfo"], "b".repeat(64));
+        assert!(ChecksumManifest::parse("SUMS", "abc123  buck2.zst\n").is_err());
+
+        let checksum = |digest: &str| {
+            let digest = digest.to_owned();
+            move || {
+                Ok(Checksum {
+                    algorithm: "sha256".to_owned(),
+                    digest,
+                })
+            }
+        };
+        assert!(manifest.verify("buck2.zst", checksum(&a)).is_ok());
+        assert!(manifest
+            .verify("buck2.zst", checksum(&"c".repeat(64)))
+            .is_err());
+        let e = manifest.verify("missing", checksum(&a)).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "the checksum manifest SUMS has no entry for missing"
+        );
+    }
+
+    #[test]
+    fn test_move_file_across_filesystems() {
+        let from_dir = tempfile::tempdir().unwrap();
+        let to_dir = tempfile::tempdir().unwrap();
+        let from = from_dir.path().join("unpacked");
+        let to = to_dir.path().join("buck2");
+        fs::write(&from, "new").unwrap();
+        fs::write(&to, "old").unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o755)).unwrap();
+
+        // Two temporary directories are usually on the same filesystem, so the failure is faked.
+        move_file_with(&from, &to, |_, _| {
+            Err(std::io::Error::from(std::io::ErrorKind::CrossesDevices))
+        })
+        .unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(&to).unwrap(), "new");
+        #[cfg(unix)]
+        assert_eq!(
+            fs::metadata(&to).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+        assert_eq!(fs::read_dir(to_dir.path()).unwrap().count(), 1);
+    }
+}
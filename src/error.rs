@@ -0,0 +1,84 @@
+//! buckle's failures, for callers that need to tell them apart.
+//!
+//! Internally errors travel as `anyhow::Error`; the ones worth matching on are a `BuckleError`
+//! inside it. The public API hands out the `BuckleError` itself.
+
+use crate::releases::BUCK_RELEASE_URL;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuckleError {
+    /// No release matches the configured `version`.
+    VersionNotFound { version: String },
+    /// Fetching `url` failed. `status` is the HTTP status, or `None` if the server couldn't be
+    /// reached. `reason` says what went wrong, URL included.
+    DownloadFailed {
+        url: String,
+        status: Option<u16>,
+        reason: String,
+    },
+    /// The cached files in `path` can't be used. Removing `path` fixes it.
+    CacheCorrupt { path: PathBuf, reason: String },
+    /// The configuration couldn't be read or doesn't make sense.
+    ConfigInvalid { reason: String },
+    /// There is nowhere to keep the cache: the home directory is unknown and no cache directory is
+    /// configured.
+    NoCacheDir,
+    /// The prelude submodule at `path` is checked out at `found` rather than `expected`.
+    PreludeMismatch {
+        path: PathBuf,
+        found: String,
+        expected: String,
+    },
+    /// Any other failure.
+    Other(anyhow::Error),
+}
+
+impl fmt::Display for BuckleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuckleError::VersionNotFound { version } if version.starts_with(">=") => write!(
+                f,
+                "no buck2 release satisfies {version}. Please check '{BUCK_RELEASE_URL}' for available releases."
+            ),
+            BuckleError::VersionNotFound { version } => write!(
+                f,
+                "{version} was not available. Please check '{BUCK_RELEASE_URL}' for available releases."
+            ),
+            BuckleError::DownloadFailed { reason, .. } => write!(f, "{reason}"),
+            BuckleError::CacheCorrupt { path, reason } => write!(
+                f,
+                "{reason}. Suggested fix is to remove {}",
+                path.display()
+            ),
+            BuckleError::ConfigInvalid { reason } => {
+                write!(f, "Failed to read configuration: {reason}")
+            }
+            BuckleError::NoCacheDir => write!(
+                f,
+                "could not find a cache directory because the home directory is unknown. Either set $HOME or specify a $BUCKLE_CACHE"
+            ),
+            BuckleError::PreludeMismatch {
+                found, expected, ..
+            } => write!(
+                f,
+                "Git submodule for prelude ({found}) is not the expected {expected}."
+            ),
+            BuckleError::Other(e) => write!(f, "{e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for BuckleError {}
+
+/// Recover the `BuckleError` inside, if there is one.
+impl From<anyhow::Error> for BuckleError {
+    fn from(e: anyhow::Error) -> BuckleError {
+        match e.downcast::<BuckleError>() {
+            Ok(e) => e,
+            Err(e) => BuckleError::Other(e),
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! The HTTP client every request goes through, and the download plumbing around it.
+
+use crate::config::BuckleConfig;
+use crate::error::BuckleError;
+use anyhow::{anyhow, Error};
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+/// An HTTP client that refuses to contact hosts outside `BUCKLE_ALLOWED_HOSTS`, if it is set.
+///
+/// Every request buckle makes goes through `get`, so the check happens before anything is sent.
+/// Redirects are checked too.
+pub(crate) struct HttpClient {
+    pub(crate) inner: reqwest::blocking::Client,
+    pub(crate) allowed_hosts: Option<Vec<String>>,
+}
+
+impl HttpClient {
+    pub(crate) fn get(&self, url: &str) -> Result<reqwest::blocking::RequestBuilder, Error> {
+        let parsed = Url::parse(url).map_err(|e| anyhow!("invalid URL {url}: {e}"))?;
+        if !host_allowed(self.allowed_hosts.as_deref(), &parsed) {
+            return Err(anyhow!(
+                "refusing to connect to {}, which is not in BUCKLE_ALLOWED_HOSTS ({url})",
+                parsed.host_str().unwrap_or_default()
+            ));
+        }
+        Ok(self.inner.get(parsed))
+    }
+}
+
+/// Send `request`, with a hint about TLS-inspecting proxies if the server's certificate couldn't
+/// be verified. Every request buckle makes is sent through here.
+pub(crate) fn send(
+    request: reqwest::blocking::RequestBuilder,
+) -> reqwest::Result<reqwest::blocking::Response> {
+    request.send().inspect_err(|e| {
+        if is_certificate_error(e) {
+            let host = e.url().and_then(|url| url.host_str()).unwrap_or_default();
+            warn!("TLS verification failed connecting to {host}; if you're behind a TLS-inspecting proxy set BUCKLE_CA_BUNDLE to a file with its CA certificate.");
+        }
+    })
+}
+
+/// Whether `e` failed because the server's certificate wasn't trusted, as opposed to e.g. a
+/// refused connection.
+pub(crate) fn is_certificate_error(e: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(mut err) = source {
+        // rustls's error reaches us wrapped in `io::Error`s, whose `source` skips past what they
+        // wrap.
+        while let Some(inner) = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|io| io.get_ref())
+        {
+            err = inner;
+        }
+        if let Some(rustls::Error::InvalidCertificate(_)) = err.downcast_ref() {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// Whether `url` points at one of `allowed_hosts`. Without a list every host is allowed.
+pub(crate) fn host_allowed(allowed_hosts: Option<&[String]>, url: &Url) -> bool {
+    let Some(allowed_hosts) = allowed_hosts else {
+        return true;
+    };
+    url.host_str().is_some_and(|host| {
+        allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    })
+}
+
+/// What a client is built from, to tell whether the shared one suits a config.
+#[derive(PartialEq)]
+struct ClientSettings {
+    user_agent: String,
+    allowed_hosts: Option<Vec<String>>,
+    ca_bundle: Option<PathBuf>,
+    danger_accept_invalid_certs: bool,
+}
+
+/// The HTTP client for every request buckle makes, so they all share the TLS settings.
+///
+/// It is built once and shared, so the release lookup, the download and the prelude hash reuse
+/// its connections, over HTTP/2 where the server offers it, rather than each paying for a TCP and
+/// TLS handshake.
+pub(crate) fn http_client(config: &BuckleConfig) -> Result<Arc<HttpClient>, Error> {
+    // One per distinct set of settings, which in practice means one.
+    static SHARED: Mutex<Vec<(ClientSettings, Arc<HttpClient>)>> = Mutex::new(Vec::new());
+    let settings = ClientSettings {
+        user_agent: config.user_agent.clone(),
+        allowed_hosts: config.allowed_hosts.clone(),
+        ca_bundle: config.ca_bundle.clone(),
+        danger_accept_invalid_certs: config.danger_accept_invalid_certs,
+    };
+    let mut shared = SHARED.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Some((_, client)) = shared.iter().find(|(shared, _)| *shared == settings) {
+        return Ok(client.clone());
+    }
+    let client = Arc::new(build_http_client(config)?);
+    shared.push((settings, client.clone()));
+    Ok(client)
+}
+
+fn build_http_client(config: &BuckleConfig) -> Result<HttpClient, Error> {
+    let mut builder = reqwest::blocking::Client::builder().user_agent(&config.user_agent);
+    if let Some(allowed_hosts) = config.allowed_hosts.clone() {
+        builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+            if !host_allowed(Some(&allowed_hosts), attempt.url()) {
+                let host = attempt.url().host_str().unwrap_or_default().to_owned();
+                attempt.error(format!(
+                    "redirected to {host}, which is not in BUCKLE_ALLOWED_HOSTS"
+                ))
+            } else if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        }));
+    }
+    if let Some(ca_bundle) = &config.ca_bundle {
+        let pem = fs::read(ca_bundle)
+            .map_err(|e| anyhow!("failed to read CA bundle {}: {e}", ca_bundle.display()))?;
+        // Not `Certificate::from_pem_bundle`, which hands rustls the certificates in a form it
+        // silently ignores.
+        let certs = rustls_pemfile::certs(&mut pem.as_slice())
+            .map_err(|e| anyhow!("invalid CA bundle {}: {e}", ca_bundle.display()))?
+            .iter()
+            .map(|der| reqwest::Certificate::from_der(der))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("invalid CA bundle {}: {e}", ca_bundle.display()))?;
+        if certs.is_empty() {
+            return Err(anyhow!(
+                "CA bundle {} contains no certificates",
+                ca_bundle.display()
+            ));
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if config.danger_accept_invalid_certs {
+        warn!("TLS certificate verification is DISABLED (BUCKLE_DANGER_ACCEPT_INVALID_CERTS). Downloads can be tampered with; only use this for debugging.");
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    Ok(HttpClient {
+        inner: builder.build()?,
+        allowed_hosts: config.allowed_hosts.clone(),
+    })
+}
+
+/// A writer that fails once more than `remaining` bytes have been written to it, if set.
+pub(crate) struct LimitedWriter<W> {
+    pub(crate) inner: W,
+    pub(crate) remaining: Option<u64>,
+    pub(crate) exceeded: bool,
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if let Some(remaining) = &mut self.remaining {
+            if buf.len() as u64 > *remaining {
+                self.exceeded = true;
+                return Err(std::io::Error::other("decompressed size limit exceeded"));
+            }
+            *remaining -= buf.len() as u64;
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Download `url` into `part_path`, resuming from whatever an earlier interrupted attempt left
+/// there.
+///
+/// A resume is requested with a `Range` header. Servers that don't support ranges answer with the
+/// whole file, in which case we start over, as we do when the range sent back doesn't start where
+/// the partial file ends. We only return once the file is complete; on failure
+/// the partial file is kept for the next attempt. Returns the number of bytes transferred.
+pub(crate) fn fetch_resumable(
+    client: &HttpClient,
+    url: &str,
+    part_path: &Path,
+) -> Result<u64, Error> {
+    use reqwest::header::{CONTENT_RANGE, RANGE};
+    use reqwest::StatusCode;
+
+    let existing = fs::metadata(part_path).map(|m| m.len()).unwrap_or(0);
+    let mut request = client.get(url)?;
+    if existing > 0 {
+        request = request.header(RANGE, format!("bytes={existing}-"));
+    }
+    let resp = send(request).map_err(|e| BuckleError::DownloadFailed {
+        url: url.to_owned(),
+        status: None,
+        reason: format!("downloading {url} failed ({e})"),
+    })?;
+    if resp.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // Whatever we have doesn't line up with the file on the server. Start over.
+        fs::remove_file(part_path)?;
+        return fetch_resumable(client, url, part_path);
+    }
+    let resp = check_download_response(url, resp)?;
+
+    let (mut file, expected_len) = match resp.status() {
+        StatusCode::PARTIAL_CONTENT => {
+            // `Content-Range: bytes <start>-<end>/<total>`
+            let range = resp
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            let start = range
+                .strip_prefix("bytes ")
+                .and_then(|r| r.split_once('-'))
+                .and_then(|(start, _)| start.parse::<u64>().ok());
+            if start != Some(existing) {
+                if existing == 0 {
+                    return Err(anyhow!(
+                        "{url} answered with a range we didn't ask for ('{range}')"
+                    ));
+                }
+                // Appending a range that doesn't start where we left off would corrupt the
+                // file. Start over.
+                drop(resp);
+                fs::remove_file(part_path)?;
+                return fetch_resumable(client, url, part_path);
+            }
+            let total = range
+                .rsplit_once('/')
+                .and_then(|(_, total)| total.parse::<u64>().ok());
+            let file = fs::OpenOptions::new().append(true).open(part_path)?;
+            (file, total)
+        }
+        _ => {
+            let len = resp.content_length();
+            (File::create(part_path)?, len)
+        }
+    };
+
+    let mut resp = CountingReader::new(resp);
+    std::io::copy(&mut resp, &mut file)?;
+    file.flush()?;
+
+    let len = file.metadata()?.len();
+    if let Some(expected_len) = expected_len {
+        if len != expected_len {
+            return Err(anyhow!(
+                "download of {url} is incomplete ({len} of {expected_len} bytes)"
+            ));
+        }
+    }
+    Ok(resp.count)
+}
+
+/// Turn responses that can't be the file we asked for into errors that say what happened, rather
+/// than letting an error page reach the decompressor.
+pub(crate) fn check_download_response(
+    url: &str,
+    resp: reqwest::blocking::Response,
+) -> Result<reqwest::blocking::Response, Error> {
+    let status = resp.status();
+    let failed = |reason: String| BuckleError::DownloadFailed {
+        url: url.to_owned(),
+        status: Some(status.as_u16()),
+        reason,
+    };
+    if status == reqwest::StatusCode::NOT_FOUND {
+        return Err(failed(format!("artifact not found at {url} ({status})")).into());
+    }
+    if !status.is_success() {
+        return Err(failed(format!("downloading {url} failed ({status})")).into());
+    }
+    let is_html = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().starts_with("text/html"));
+    if is_html {
+        return Err(failed(format!(
+            "{url} returned an HTML page instead of the expected file. Check base_download_url."
+        ))
+        .into());
+    }
+    Ok(resp)
+}
+
+/// Counts the bytes read through it, to report how much a download transferred.
+pub(crate) struct CountingReader<R> {
+    pub(crate) inner: R,
+    pub(crate) count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        CountingReader { inner, count: 0 }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Describe a number of bytes as e.g. `48.2 MiB`.
+pub(crate) fn human_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{value:.0} {}", UNITS[unit])
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Describe a transfer as e.g. `48.2 MiB in 3.1s, 15.5 MiB/s`.
+pub(crate) fn transfer_summary(bytes: u64, elapsed: std::time::Duration) -> String {
+    let secs = elapsed.as_secs_f64();
+    // Avoid reporting an infinite rate for downloads that finish instantly.
+    let rate = bytes as f64 / secs.max(0.001);
+    format!(
+        "{} in {secs:.1}s, {}/s",
+        human_bytes(bytes as f64),
+        human_bytes(rate)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_summary() {
+        use std::time::Duration;
+        assert_eq!(
+            transfer_summary(50_541_363, Duration::from_millis(3100)),
+            "48.2 MiB in 3.1s, 15.5 MiB/s"
+        );
+        assert_eq!(
+            transfer_summary(512, Duration::from_secs(2)),
+            "512 B in 2.0s, 256 B/s"
+        );
+    }
+
+    #[test]
+    fn test_http_client_is_shared() {
+        let config = BuckleConfig::default();
+        let first = http_client(&config).unwrap();
+        assert!(Arc::ptr_eq(&first, &http_client(&config).unwrap()));
+
+        let other = BuckleConfig {
+            allowed_hosts: Some(vec!["github.com".to_owned()]),
+            ..BuckleConfig::default()
+        };
+        let restricted = http_client(&other).unwrap();
+        assert!(!Arc::ptr_eq(&first, &restricted));
+        assert!(Arc::ptr_eq(&restricted, &http_client(&other).unwrap()));
+    }
+
+    #[test]
+    fn test_host_allowed() {
+        let url = Url::parse("https://GitHub.com/facebook/buck2").unwrap();
+        let allowed = ["api.github.com".to_owned(), "github.com".to_owned()];
+        assert!(host_allowed(None, &url));
+        assert!(host_allowed(Some(&allowed), &url));
+        assert!(!host_allowed(Some(&allowed[..1]), &url));
+        assert!(!host_allowed(Some(&[]), &url));
+    }
+}
@@ -0,0 +1,258 @@
+//! buckle fetches the buck2 release a project asks for into a per-user cache and runs it.
+//!
+//! The `buckle` binary is a thin wrapper around this crate. Tools that want the binary buckle would
+//! run, without shelling out to `buckle --buckle-which`, can resolve it themselves:
+//!
+//! ```no_run
+//! let buckle = buckle::Buckle::from_env()?;
+//! let buck2 = buckle.resolve()?;
+//! println!("buck2 {} is at {}", buck2.version, buck2.path.display());
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Configuration is read the same way the binary does, from environment variables and the nearest
+//! `.buckleconfig.toml`. `Buckle::from_config` takes a `BuckleConfig` put together by the caller.
+//! Failures are `BuckleError`s, so a missing release can be told apart from a network problem.
+
+#[doc(hidden)]
+#[macro_use]
+pub mod output;
+
+#[doc(hidden)]
+pub mod cli;
+mod config;
+mod download;
+mod error;
+mod http;
+mod platform;
+mod prelude;
+mod releases;
+mod resolve;
+
+use std::path::{Path, PathBuf};
+
+pub use config::BuckleConfig;
+pub use download::Installed;
+pub use error::BuckleError;
+pub use releases::Release;
+pub use resolve::ResolvedVersion;
+
+/// Read the configuration the `buckle` binary runs with, from environment variables, the nearest
+/// `.buckleconfig.toml` and `.buckle-version`, and defaults.
+pub fn read_config() -> Result<BuckleConfig, BuckleError> {
+    Ok(config::read_config()?)
+}
+
+/// The first page of buck2 releases, cached in `path` for four hours.
+pub fn get_releases(config: &BuckleConfig, path: &Path) -> Result<Vec<Release>, BuckleError> {
+    Ok(releases::get_releases(config, path)?)
+}
+
+/// Work out which release the configured version refers to and where in `output_dir` it is
+/// cached, without downloading the binary.
+pub fn resolve_version(
+    config: &BuckleConfig,
+    output_dir: &Path,
+) -> Result<ResolvedVersion, BuckleError> {
+    Ok(resolve::resolve_version(config, output_dir)?)
+}
+
+/// Download the configured version into `output_dir`, unless it is there already.
+pub fn download_http(config: &BuckleConfig, output_dir: &Path) -> Result<Installed, BuckleError> {
+    Ok(download::download_http(config, output_dir)?)
+}
+
+/// A buck2 binary in buckle's cache, ready to run.
+#[derive(Debug, Clone)]
+pub struct ResolvedBinary {
+    /// The `buck2` binary, or for a version cached with `cache_compression` its `buck2.zst`
+    /// archive, which has to be unpacked before it can run.
+    pub path: PathBuf,
+    /// The release it was downloaded from, e.g. `2024-09-02` or `latest`.
+    pub version: String,
+    /// The version directory it lives in.
+    pub dir: PathBuf,
+    /// Whether it was already cached rather than downloaded just now.
+    pub cache_hit: bool,
+}
+
+/// Resolves and downloads buck2 the way the `buckle` binary does.
+#[derive(Debug)]
+pub struct Buckle {
+    config: BuckleConfig,
+}
+
+impl Buckle {
+    /// Use `config` as is.
+    pub fn from_config(config: BuckleConfig) -> Buckle {
+        Buckle { config }
+    }
+
+    /// Read the configuration from the environment and the nearest `.buckleconfig.toml`.
+    pub fn from_env() -> Result<Buckle, BuckleError> {
+        Ok(Buckle::from_config(read_config()?))
+    }
+
+    pub fn config(&self) -> &BuckleConfig {
+        &self.config
+    }
+
+    /// Resolve the configured version and download it unless it is cached already.
+    pub fn resolve(&self) -> Result<ResolvedBinary, BuckleError> {
+        if self.config.buckle_dir.as_os_str().is_empty() {
+            return Err(BuckleError::NoCacheDir);
+        }
+        let installed = download::get_buck2_dir(&self.config)?;
+        Ok(ResolvedBinary {
+            path: installed.binary(),
+            version: installed.tag,
+            dir: installed.dir,
+            cache_hit: installed.cache_hit,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+
+    /// Serve `body` with `status` to every request on a local port, returning its base URL.
+    fn serve(status: &'static str, body: Vec<u8>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|n| n > 2) {
+                    line.clear();
+                }
+                let head = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&body);
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn test_resolve_downloads_then_hits_cache() {
+        let script = b"#!/bin/sh\necho buck2\n";
+        let url = serve("200 OK", zstd::encode_all(&script[..], 0).unwrap());
+        let cache = tempfile::tempdir().unwrap();
+        let buckle = Buckle::from_config(BuckleConfig {
+            buck2_version: "2024-09-02".to_owned(),
+            base_download_url: url,
+            check_prelude: false,
+            buckle_dir: cache.path().to_path_buf(),
+            ..BuckleConfig::default()
+        });
+
+        let buck2 = buckle.resolve().unwrap();
+        assert_eq!(buck2.version, "2024-09-02");
+        assert_eq!(buck2.path, cache.path().join("2024-09-02").join("buck2"));
+        assert_eq!(std::fs::read(&buck2.path).unwrap(), script);
+        assert!(!buck2.cache_hit);
+
+        assert!(buckle.resolve().unwrap().cache_hit);
+    }
+
+    /// A config fetching `version` from `base_download_url` into `cache`.
+    fn config(version: &str, base_download_url: String, cache: &std::path::Path) -> BuckleConfig {
+        BuckleConfig {
+            buck2_version: version.to_owned(),
+            base_download_url,
+            check_prelude: false,
+            buckle_dir: cache.to_path_buf(),
+            ..BuckleConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_resolve_missing_artifact() {
+        let url = serve("404 Not Found", vec![]);
+        let cache = tempfile::tempdir().unwrap();
+        let err = Buckle::from_config(config("2024-09-02", url, cache.path()))
+            .resolve()
+            .unwrap_err();
+        match err {
+            BuckleError::DownloadFailed {
+                url,
+                status: Some(404),
+                ..
+            } => assert!(url.ends_with(".zst"), "found {url}"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_resolve_unreachable() {
+        // Nothing listens on a port that was just freed.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        let cache = tempfile::tempdir().unwrap();
+        let err = Buckle::from_config(config("2024-09-02", url, cache.path()))
+            .resolve()
+            .unwrap_err();
+        assert!(
+            matches!(err, BuckleError::DownloadFailed { status: None, .. }),
+            "found {err:?}"
+        );
+    }
+
+    #[test]
+    fn test_free_functions_return_buckle_errors() {
+        let cache = tempfile::tempdir().unwrap();
+        std::fs::write(cache.path().join("releases.json"), "[]").unwrap();
+        let config = config(
+            ">=2024-01-01",
+            "http://127.0.0.1:1".to_owned(),
+            cache.path(),
+        );
+        assert!(matches!(
+            resolve_version(&config, cache.path()),
+            Err(BuckleError::VersionNotFound { .. })
+        ));
+        assert!(matches!(
+            download_http(&config, cache.path()),
+            Err(BuckleError::VersionNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolve_without_cache_dir() {
+        let err = Buckle::from_config(BuckleConfig {
+            buckle_dir: std::path::PathBuf::new(),
+            ..config(
+                "2024-09-02",
+                "http://127.0.0.1:1".to_owned(),
+                std::path::Path::new(""),
+            )
+        })
+        .resolve()
+        .unwrap_err();
+        assert!(matches!(err, BuckleError::NoCacheDir), "found {err:?}");
+    }
+
+    #[test]
+    fn test_resolve_version_not_found() {
+        let cache = tempfile::tempdir().unwrap();
+        std::fs::write(cache.path().join("releases.json"), "[]").unwrap();
+        let err = Buckle::from_config(config(
+            ">=2024-01-01",
+            "http://127.0.0.1:1".to_owned(),
+            cache.path(),
+        ))
+        .resolve()
+        .unwrap_err();
+        match err {
+            BuckleError::VersionNotFound { version } => assert_eq!(version, ">=2024-01-01"),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
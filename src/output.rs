@@ -0,0 +1,101 @@
+//! Level-tagged diagnostics for buckle's own messages on stderr.
+//!
+//! Every line starts with `buckle:`, colored by severity when stderr is a terminal and `NO_COLOR`
+//! is unset. Without color the output is plain `buckle: <message>`. In quiet mode only warnings
+//! and errors are printed. With `BUCKLE_CI=github`, warnings and errors are GitHub Actions
+//! workflow commands instead, so they show up as annotations.
+
+use std::env;
+use std::fmt;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+fn color_choice() -> ColorChoice {
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    if no_color || !std::io::stderr().is_terminal() {
+        ColorChoice::Never
+    } else {
+        ColorChoice::Auto
+    }
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stop printing `Info` messages for the rest of the process.
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+/// `message` as a GitHub Actions workflow command such as `::warning::<message>`, escaped so a
+/// multi-line message stays one annotation.
+pub(crate) fn workflow_command(command: &str, message: &str) -> String {
+    let escaped = message
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A");
+    format!("::{command}::{escaped}")
+}
+
+fn github_annotations() -> bool {
+    env::var("BUCKLE_CI").is_ok_and(|ci| ci.eq_ignore_ascii_case("github"))
+}
+
+pub fn print(level: Level, args: fmt::Arguments) {
+    if level == Level::Info && QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    if level != Level::Info && github_annotations() {
+        let command = if level == Level::Warn {
+            "warning"
+        } else {
+            "error"
+        };
+        // The runner picks workflow commands out of stderr as well as stdout, which is buck2's.
+        eprintln!("{}", workflow_command(command, &format!("buckle: {args}")));
+        return;
+    }
+    let color = match level {
+        Level::Info => Color::Green,
+        Level::Warn => Color::Yellow,
+        Level::Error => Color::Red,
+    };
+    let mut stderr = StandardStream::stderr(color_choice());
+    // There is nowhere left to report a failure to write to stderr.
+    let _ = stderr.set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true));
+    let _ = write!(stderr, "buckle:");
+    let _ = stderr.reset();
+    let _ = writeln!(stderr, " {args}");
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::output::print($crate::output::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::output::print($crate::output::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::output::print($crate::output::Level::Error, format_args!($($arg)*)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_workflow_command() {
+        assert_eq!(
+            workflow_command("warning", "50% done\nrun this"),
+            "::warning::50%25 done%0Arun this"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Working out which buck2 artifact fits the host.
+
+use crate::config::is_termux;
+use anyhow::{anyhow, Error};
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+/// The target triple of the buck2 artifact to download for this host.
+///
+/// `BUCKLE_TARGET` replaces the detected triple entirely, for hosts where detection picks the
+/// wrong artifact (emulation, cross setups, ...). Otherwise `targets`, the `[targets]` table, may
+/// name the triple for this host's `<arch>-<os>`.
+pub(crate) fn get_arch(targets: &BTreeMap<String, String>) -> Result<String, Error> {
+    if let Ok(target) = env::var("BUCKLE_TARGET") {
+        if target.trim().is_empty() {
+            return Err(anyhow!("BUCKLE_TARGET is set but empty"));
+        }
+        return Ok(target.trim().to_owned());
+    }
+    host_target(env::consts::ARCH, env::consts::OS, targets)
+}
+
+/// The target triple for an `arch`/`os` host, as Rust names them: the one `targets` lists for
+/// `<arch>-<os>`, or else the built-in one.
+pub(crate) fn host_target(
+    arch: &str,
+    os: &str,
+    targets: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    if let Some(target) = targets.get(&format!("{arch}-{os}")) {
+        return Ok(target.clone());
+    }
+
+    Ok(match arch {
+        "x86_64" => match os {
+            "linux" => format!("x86_64-unknown-linux-{}", host_libc()?.as_str()),
+            "darwin" | "macos" => "x86_64-apple-darwin".to_owned(),
+            "windows" => "x86_64-pc-windows-msvc".to_owned(),
+            unknown => return Err(anyhow!("Unsupported Arch/OS: x86_64/{unknown}. Set [targets] \"x86_64-{unknown}\" to the triple to download.")),
+        },
+        "aarch64" => match os {
+            "linux" => format!("aarch64-unknown-linux-{}", host_libc()?.as_str()),
+            "darwin" | "macos" => "aarch64-apple-darwin".to_owned(),
+            unknown => return Err(anyhow!("Unsupported Arch/OS: aarch64/{unknown}. Set [targets] \"aarch64-{unknown}\" to the triple to download.")),
+        },
+        arch => return Err(anyhow!("Unsupported Architecture: {arch}. Set [targets] \"{arch}-{os}\" to the triple to download.")),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Libc {
+    Gnu,
+    Musl,
+}
+
+impl Libc {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Libc::Gnu => "gnu",
+            Libc::Musl => "musl",
+        }
+    }
+}
+
+/// The libc of this Linux host. `BUCKLE_LIBC` (`gnu` or `musl`) takes precedence over detection.
+///
+/// Android has neither, but the statically linked musl builds run there, so Termux gets those.
+pub(crate) fn host_libc() -> Result<Libc, Error> {
+    match env::var("BUCKLE_LIBC") {
+        Ok(libc) => match libc.to_lowercase().as_str() {
+            "gnu" | "glibc" => Ok(Libc::Gnu),
+            "musl" => Ok(Libc::Musl),
+            other => Err(anyhow!(
+                "BUCKLE_LIBC must be 'gnu' or 'musl', not '{other}'"
+            )),
+        },
+        Err(_) if is_termux(|name| env::var(name).ok()) => Ok(Libc::Musl),
+        Err(_) => Ok(detect_libc(Path::new("/lib"))),
+    }
+}
+
+/// musl systems ship their dynamic loader as `/lib/ld-musl-<arch>.so.1`; anything else is assumed
+/// to be glibc.
+pub(crate) fn detect_libc(lib_dir: &Path) -> Libc {
+    let musl = fs::read_dir(lib_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|entry| entry.file_name().to_string_lossy().starts_with("ld-musl-"))
+        })
+        .unwrap_or(false);
+    if musl {
+        Libc::Musl
+    } else {
+        Libc::Gnu
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_target_overrides() {
+        let targets = BTreeMap::from([
+            (
+                "x86_64-macos".to_owned(),
+                "x86_64-apple-darwin-custom".to_owned(),
+            ),
+            (
+                "riscv64-linux".to_owned(),
+                "riscv64gc-unknown-linux-gnu".to_owned(),
+            ),
+        ]);
+        assert_eq!(
+            host_target("x86_64", "macos", &targets).unwrap(),
+            "x86_64-apple-darwin-custom"
+        );
+        assert_eq!(
+            host_target("riscv64", "linux", &targets).unwrap(),
+            "riscv64gc-unknown-linux-gnu"
+        );
+        // Hosts without an entry keep the built-in triple.
+        assert_eq!(
+            host_target("aarch64", "macos", &targets).unwrap(),
+            "aarch64-apple-darwin"
+        );
+        assert!(host_target("riscv64", "linux", &BTreeMap::new()).is_err());
+    }
+
+    #[test]
+    fn test_detect_libc() {
+        let lib = tempfile::tempdir().unwrap();
+        fs::write(lib.path().join("ld-linux-x86-64.so.2"), "").unwrap();
+        assert_eq!(detect_libc(lib.path()), Libc::Gnu);
+
+        fs::write(lib.path().join("ld-musl-x86_64.so.1"), "").unwrap();
+        assert_eq!(detect_libc(lib.path()), Libc::Musl);
+
+        assert_eq!(detect_libc(&lib.path().join("missing")), Libc::Gnu);
+    }
+}
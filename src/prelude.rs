@@ -0,0 +1,369 @@
+//! Checking that the project's prelude matches the buck2 release.
+
+use crate::config::{get_buck2_project_root, BuckleConfig};
+use crate::download::{Installed, PRELUDE_HASH_FILE};
+use crate::error::BuckleError;
+use anyhow::{anyhow, Error};
+use ini::Ini;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::{fs, path::Path};
+
+/// The prelude hash downloaded with `installed`.
+pub(crate) fn get_expected_prelude_hash(installed: &Installed) -> Result<String, Error> {
+    let prelude_hash_path = installed.dir.join(PRELUDE_HASH_FILE);
+    let prelude_hash =
+        fs::read_to_string(&prelude_hash_path).map_err(|e| BuckleError::CacheCorrupt {
+            path: installed.dir.clone(),
+            reason: format!("failed to read {}: {e}", prelude_hash_path.display()),
+        })?;
+    Ok(prelude_hash.trim().to_owned())
+}
+
+/// The fewest hex digits an abbreviated hash needs to match a full one. Git itself abbreviates to
+/// at least seven.
+const MIN_ABBREVIATED_HASH_LEN: usize = 7;
+
+/// Whether two git hashes name the same commit, ignoring case and surrounding whitespace. One may
+/// be abbreviated, as long as it keeps `MIN_ABBREVIATED_HASH_LEN` digits.
+pub(crate) fn prelude_hashes_match(a: &str, b: &str) -> bool {
+    let (a, b) = (a.trim().to_ascii_lowercase(), b.trim().to_ascii_lowercase());
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    short == long || (short.len() >= MIN_ABBREVIATED_HASH_LEN && long.starts_with(&short))
+}
+
+/// Where a cached prelude hash came from, stored as `prelude.json` in the version directory.
+///
+/// Caches can be shared between projects that download the same version from different mirrors,
+/// so the hash on disk isn't necessarily the one this project's buck2 expects.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PreludeMetadata {
+    pub(crate) hash: String,
+    pub(crate) version: String,
+    pub(crate) base_download_url: String,
+}
+
+impl PreludeMetadata {
+    pub(crate) const FILE: &'static str = "prelude.json";
+
+    pub(crate) fn read(dir: &Path) -> Option<PreludeMetadata> {
+        let buf = fs::read_to_string(dir.join(Self::FILE)).ok()?;
+        serde_json::from_str(&buf).ok()
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        fs::write(dir.join(Self::FILE), serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Explain why this metadata doesn't describe `installed` as this project configures it.
+    pub(crate) fn mismatch(&self, config: &BuckleConfig, installed: &Installed) -> Option<String> {
+        if self.version != installed.tag {
+            Some(format!(
+                "the cached prelude hash belongs to buck2 {}, not {}",
+                self.version, installed.tag
+            ))
+        } else if self.base_download_url != config.base_download_url {
+            Some(format!(
+                "the cached prelude hash for buck2 {} was downloaded from {}, but this project downloads from {}",
+                self.version, self.base_download_url, config.base_download_url
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether `installed` has a prelude hash to check preludes against, warning if it doesn't or if
+/// the hash may not be the one this project expects.
+pub(crate) fn prelude_hash_usable(config: &BuckleConfig, installed: &Installed) -> bool {
+    // The version may have been downloaded while the prelude check was off.
+    if !installed.dir.join(PRELUDE_HASH_FILE).exists() {
+        warn!(
+            "no prelude hash was downloaded for buck2 {}, so the prelude can't be checked. Remove {} to fetch it.",
+            installed.tag,
+            installed.dir.display()
+        );
+        return false;
+    }
+
+    // Caches written before the metadata existed have nothing to compare against.
+    if let Some(mismatch) =
+        PreludeMetadata::read(&installed.dir).and_then(|meta| meta.mismatch(config, installed))
+    {
+        warn!(
+            "{mismatch}. Remove {} to fetch the right one.",
+            installed.dir.display()
+        );
+    }
+    true
+}
+
+// Warn if the prelude does not match expected
+pub(crate) fn verify_prelude(prelude_path: &str, expected_hash: &str) -> Result<(), Error> {
+    if let Some(project_root) = get_buck2_project_root()? {
+        let mut absolute_prelude_path = project_root.to_path_buf();
+        absolute_prelude_path.push(prelude_path);
+        // It's ok if it's not a git repo, but we don't have support
+        // for checking other methods yet. Do not throw an error.
+        if let Ok(repo) = git2::Repository::open_from_env() {
+            // It makes no sense for buck2 to be invoked on a bare git repo.
+            let git_workdir = repo
+                .workdir()
+                .ok_or(anyhow!("buck2 is not for bare git repos"))?;
+            let git_relative_prelude_path = absolute_prelude_path
+                .strip_prefix(git_workdir)
+                .map_err(|_err| {
+                    anyhow!(
+                        "{}/.buckconfig indicates the prelude should be \
+                        located at {} which is not within this git repo.",
+                        project_root.display(),
+                        absolute_prelude_path.display(),
+                    )
+                })?
+                .to_str()
+                .ok_or(anyhow!("Could not convert the prelude path to a string"))?;
+            // If there is a prelude known
+            if let Ok(prelude) = repo.find_submodule(git_relative_prelude_path) {
+                // Don't check if there is no ID.
+                if let Some(prelude_hash) = prelude.workdir_id() {
+                    let prelude_hash = prelude_hash.to_string();
+                    if !prelude_hashes_match(&prelude_hash, expected_hash) {
+                        return Err(BuckleError::PreludeMismatch {
+                            path: absolute_prelude_path,
+                            found: prelude_hash,
+                            expected: expected_hash.to_owned(),
+                        }
+                        .into());
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Load a `.buckconfig`, inlining any files it pulls in with `<file:...>` or `<?file:...>`.
+///
+/// Includes are resolved relative to the including file. One that can't be read is skipped, so
+/// we fall back to whatever the including file itself says.
+pub(crate) fn load_buckconfig(path: &Path) -> Result<Ini, Error> {
+    fn expand(path: &Path, depth: usize, out: &mut String) -> Result<(), Error> {
+        let contents = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        for line in contents.lines() {
+            let include = line
+                .trim()
+                .strip_prefix("<file:")
+                .or_else(|| line.trim().strip_prefix("<?file:"))
+                .and_then(|rest| rest.strip_suffix('>'));
+            match include {
+                // Guard against include cycles.
+                Some(include) if depth < 8 => {
+                    let _ = expand(&dir.join(include), depth + 1, out);
+                }
+                Some(_) => {}
+                None => {
+                    out.push_str(line);
+                    out.push('\n');
+                }
+            }
+        }
+        Ok(())
+    }
+
+    let mut contents = String::new();
+    expand(path, 0, &mut contents)?;
+    Ok(Ini::load_from_str(&contents)?)
+}
+
+/// Find the preludes declared in a `.buckconfig`, as `(cell, path)` pairs: the `prelude` cell, and
+/// every cell with an expected hash in `prelude_hashes`, for monorepos that carry several.
+///
+/// Older buck2 projects declare cells under `[repositories]` and newer ones under `[cells]`. A
+/// configured section name is tried before either of those, and the first section with a prelude
+/// in it is used.
+pub(crate) fn find_prelude_paths<'a>(
+    ini: &'a Ini,
+    extra_section: Option<&'a str>,
+    prelude_hashes: &BTreeMap<String, String>,
+) -> Vec<(&'a str, &'a str)> {
+    extra_section
+        .into_iter()
+        .chain(["repositories", "cells"])
+        .filter_map(|section| ini.section(Some(section)))
+        .map(|section| {
+            section
+                .iter()
+                .filter(|(cell, _)| *cell == "prelude" || prelude_hashes.contains_key(*cell))
+                .collect::<Vec<_>>()
+        })
+        .find(|paths| !paths.is_empty())
+        .unwrap_or_default()
+}
+
+/// Notify user of prelude mismatch and suggest solution.
+// TODO make this much better
+pub(crate) fn mismatched_prelude_msg(
+    absolute_prelude_path: &Path,
+    prelude_hash: &str,
+    expected_hash: &str,
+) {
+    warn!("Git submodule for prelude ({prelude_hash}) is not the expected {expected_hash}.");
+    warn!("{}", prelude_fix(absolute_prelude_path, expected_hash));
+}
+
+/// The commands that check the prelude at `absolute_prelude_path` out at `expected_hash`.
+pub(crate) fn prelude_fix(absolute_prelude_path: &Path, expected_hash: &str) -> String {
+    let abs_path = absolute_prelude_path.display();
+    format!("cd {abs_path} && git fetch && git checkout {expected_hash}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_prelude_path_repositories() {
+        let ini = Ini::load_from_str("[repositories]\nroot = .\nprelude = prelude\n").unwrap();
+        assert_eq!(
+            find_prelude_paths(&ini, None, &BTreeMap::new()),
+            [("prelude", "prelude")]
+        );
+    }
+
+    #[test]
+    fn test_find_prelude_path_cells() {
+        let ini = Ini::load_from_str("[cells]\nroot = .\nprelude = third-party/prelude\n").unwrap();
+        assert_eq!(
+            find_prelude_paths(&ini, None, &BTreeMap::new()),
+            [("prelude", "third-party/prelude")]
+        );
+    }
+
+    #[test]
+    fn test_find_prelude_path_prefers_configured_section() {
+        let ini = Ini::load_from_str(
+            "[cells]\nprelude = prelude\n[my_cells]\nprelude = vendor/prelude\n",
+        )
+        .unwrap();
+        assert_eq!(
+            find_prelude_paths(&ini, Some("my_cells"), &BTreeMap::new()),
+            [("prelude", "vendor/prelude")]
+        );
+        assert_eq!(
+            find_prelude_paths(&ini, Some("missing"), &BTreeMap::new()),
+            [("prelude", "prelude")]
+        );
+    }
+
+    #[test]
+    fn test_load_buckconfig_follows_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("config")).unwrap();
+        fs::write(
+            dir.path().join(".buckconfig"),
+            "<file:config/cells.bcfg>\n<?file:config/missing.bcfg>\n[build]\nexecution_platforms = root//:platforms\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("config/cells.bcfg"),
+            "[cells]\nroot = .\nprelude = prelude\n",
+        )
+        .unwrap();
+
+        let ini = load_buckconfig(&dir.path().join(".buckconfig")).unwrap();
+        assert_eq!(
+            find_prelude_paths(&ini, None, &BTreeMap::new()),
+            [("prelude", "prelude")]
+        );
+        assert_eq!(
+            ini.get_from(Some("build"), "execution_platforms"),
+            Some("root//:platforms")
+        );
+    }
+
+    #[test]
+    fn test_load_buckconfig_unresolved_include() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(".buckconfig"),
+            "<file:nowhere.bcfg>\n[repositories]\nprelude = prelude\n",
+        )
+        .unwrap();
+
+        let ini = load_buckconfig(&dir.path().join(".buckconfig")).unwrap();
+        assert_eq!(
+            find_prelude_paths(&ini, None, &BTreeMap::new()),
+            [("prelude", "prelude")]
+        );
+    }
+
+    #[test]
+    fn test_find_prelude_path_missing() {
+        let ini = Ini::load_from_str("[cells]\nroot = .\n").unwrap();
+        assert!(find_prelude_paths(&ini, None, &BTreeMap::new()).is_empty());
+    }
+
+    #[test]
+    fn test_prelude_hashes_match() {
+        let full = "0123456789abcdef0123456789abcdef01234567";
+        assert!(prelude_hashes_match(full, full));
+        assert!(prelude_hashes_match(
+            full,
+            " 0123456789ABCDEF0123456789ABCDEF01234567\n"
+        ));
+        assert!(prelude_hashes_match(full, "0123456"));
+        assert!(prelude_hashes_match("0123456789AB", full));
+        assert!(!prelude_hashes_match(full, "012345"));
+        assert!(!prelude_hashes_match(full, ""));
+        assert!(!prelude_hashes_match(
+            full,
+            "0123456789abcdef0123456789abcdef01234568"
+        ));
+        assert!(!prelude_hashes_match(full, "1234567"));
+    }
+
+    #[test]
+    fn test_find_prelude_paths_several() {
+        let ini = Ini::load_from_str(
+            "[cells]\nroot = .\nprelude = prelude\nvendor_prelude = vendor/prelude\nlegacy-prelude = legacy/prelude\ntoolchains = toolchains\n",
+        )
+        .unwrap();
+        // Only cells with an expected hash are checked besides `prelude`, whatever they're called.
+        let prelude_hashes = BTreeMap::from([
+            ("vendor_prelude".to_owned(), "0123456".to_owned()),
+            ("toolchains".to_owned(), "89abcde".to_owned()),
+            ("missing".to_owned(), "fedcba9".to_owned()),
+        ]);
+        assert_eq!(
+            find_prelude_paths(&ini, None, &prelude_hashes),
+            [
+                ("prelude", "prelude"),
+                ("vendor_prelude", "vendor/prelude"),
+                ("toolchains", "toolchains")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_expected_prelude_hash_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let installed = Installed {
+            tag: "2024-01-01".to_owned(),
+            dir: dir.path().to_path_buf(),
+            cache_hit: true,
+        };
+        let err = get_expected_prelude_hash(&installed).unwrap_err();
+        assert!(
+            matches!(err.downcast_ref(), Some(BuckleError::CacheCorrupt { path, .. }) if path == dir.path()),
+            "found {err}"
+        );
+
+        fs::write(dir.path().join("prelude_hash"), "0123456789ab\n").unwrap();
+        assert_eq!(
+            get_expected_prelude_hash(&installed).unwrap(),
+            "0123456789ab"
+        );
+    }
+}
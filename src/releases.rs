@@ -0,0 +1,610 @@
+//! Looking buck2 releases up in the GitHub releases API.
+
+use crate::config::{set_mtime, source_date_epoch, write_atomically, BuckleConfig};
+use crate::http::{http_client, send, HttpClient};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use url::Url;
+
+pub(crate) const BUCK_RELEASE_URL: &str = "https://github.com/facebook/buck2/tags";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub struct Release {
+    pub url: Url,
+    pub html_url: Url,
+    pub assets_url: Url,
+    pub upload_url: String,
+    pub tarball_url: Option<Url>,
+    pub zipball_url: Option<Url>,
+    pub id: usize,
+    pub node_id: String,
+    pub tag_name: String,
+    pub target_commitish: String,
+    pub name: Option<String>,
+    pub body: Option<String>,
+    pub draft: bool,
+    pub prerelease: bool,
+    pub created_at: Option<String>,
+    pub published_at: Option<String>,
+    pub author: serde_json::Value,
+    pub assets: Vec<serde_json::Value>,
+}
+
+/// When the rate limit `resp` ran into resets, if it is a rate-limit rejection.
+///
+/// GitHub answers 403 both for rate limits and for missing permissions; only the former comes
+/// with `x-ratelimit-remaining: 0`.
+pub(crate) fn rate_limit_reset(resp: &reqwest::blocking::Response) -> Option<String> {
+    let status = resp.status();
+    if status != reqwest::StatusCode::FORBIDDEN && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+    {
+        return None;
+    }
+    let header = |name: &str| resp.headers().get(name).and_then(|v| v.to_str().ok());
+    if header("x-ratelimit-remaining") != Some("0") {
+        return None;
+    }
+    Some(
+        match header("x-ratelimit-reset").and_then(|v| v.parse().ok()) {
+            Some(reset) => format_utc(reset),
+            None => "the rate limit window resets".to_owned(),
+        },
+    )
+}
+
+/// Seconds since the Unix epoch as e.g. `2023-11-14 22:13:20 UTC`.
+pub(crate) fn format_utc(secs: u64) -> String {
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// The buck2 releases endpoint of the configured GitHub API.
+pub(crate) fn releases_api_url(config: &BuckleConfig) -> String {
+    format!(
+        "{}/repos/facebook/buck2/releases",
+        config.github_api_base.trim_end_matches('/')
+    )
+}
+
+/// The first page of the releases list, as long as `releases_per_page` makes it. GitHub's default
+/// of 30 can leave a recent pinned version a page further along.
+pub(crate) fn releases_list_url(config: &BuckleConfig) -> String {
+    format!(
+        "{}?per_page={}",
+        releases_api_url(config),
+        config.releases_per_page
+    )
+}
+
+/// Where the releases list is on disk, for a `file://` GitHub API base such as a synced mirror or
+/// a test fixture. It is the file at `<base>/repos/facebook/buck2/releases`, as the API lays it
+/// out.
+pub(crate) fn local_releases_path(config: &BuckleConfig) -> Option<PathBuf> {
+    Url::parse(&releases_api_url(config))
+        .ok()
+        .filter(|url| url.scheme() == "file")?
+        .to_file_path()
+        .ok()
+}
+
+/// Read the complete releases list of a `file://` GitHub API base.
+pub(crate) fn read_local_releases(path: &Path) -> Result<Vec<Release>, Error> {
+    let buf = fs::read_to_string(path)
+        .map_err(|e| anyhow!("failed to read the releases list {}: {e}", path.display()))?;
+    serde_json::from_str(&buf).map_err(|e| anyhow!("invalid releases list {}: {e}", path.display()))
+}
+
+/// The first page of buck2 releases, from `path/releases.json` if it was fetched in the last four
+/// hours and `--buckle-reresolve` wasn't given. A `file://` API base is read directly instead.
+pub(crate) fn get_releases(config: &BuckleConfig, path: &Path) -> Result<Vec<Release>, Error> {
+    if let Some(local) = local_releases_path(config) {
+        return read_local_releases(&local);
+    }
+    let mut releases_json_path = path.to_path_buf();
+    releases_json_path.push("releases.json");
+
+    // TODO support last last_modification_time for windows users
+    #[cfg(unix)]
+    if releases_json_path.exists() && !config.reresolve {
+        use std::os::unix::fs::MetadataExt;
+        // A list cut short by an interrupted write is fetched again rather than trusted.
+        match read_cached_releases(&releases_json_path) {
+            Ok((releases, fetched_at)) => {
+                let last_modification_time = match fetched_at {
+                    Some(fetched_at) => fetched_at as i64,
+                    None => fs::metadata(&releases_json_path)?.mtime(),
+                };
+                let curr_time = SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)?
+                    .as_secs() as i64;
+                if (curr_time - last_modification_time).abs() < 4 * 60 * 60 {
+                    return Ok(releases);
+                }
+            }
+            Err(e) => warn!(
+                "the cached releases list {} is unreadable ({e}), fetching it again",
+                releases_json_path.display()
+            ),
+        }
+    }
+
+    refresh_releases(config, path)
+}
+
+/// Like `get_releases`, but fetch the list again whatever is cached in `path/releases.json`.
+pub(crate) fn refresh_releases(config: &BuckleConfig, path: &Path) -> Result<Vec<Release>, Error> {
+    if let Some(local) = local_releases_path(config) {
+        return read_local_releases(&local);
+    }
+    let client = http_client(config)?;
+    fetch_releases(
+        &client,
+        &releases_list_url(config),
+        std::time::Duration::from_secs(config.releases_api_timeout_secs),
+        &path.join("releases.json"),
+    )
+}
+
+/// `releases.json` as written while `SOURCE_DATE_EPOCH` pins its mtime, with the time it was
+/// fetched recorded inside instead. Otherwise it is the plain list.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum CachedReleases {
+    Stamped {
+        fetched_at: u64,
+        releases: Vec<Release>,
+    },
+    Plain(Vec<Release>),
+}
+
+/// The cached releases list, and when it was fetched if that was recorded inside it.
+pub(crate) fn read_cached_releases(path: &Path) -> Result<(Vec<Release>, Option<u64>), Error> {
+    Ok(match serde_json::from_str(&fs::read_to_string(path)?)? {
+        CachedReleases::Stamped {
+            fetched_at,
+            releases,
+        } => (releases, Some(fetched_at)),
+        CachedReleases::Plain(releases) => (releases, None),
+    })
+}
+
+pub(crate) fn write_cached_releases(path: &Path, releases: &[Release]) -> Result<(), Error> {
+    // Written atomically, as an interrupted write would leave a list that can't be read.
+    let Some(epoch) = source_date_epoch() else {
+        return write_atomically(path, serde_json::to_string(releases)?);
+    };
+    let cached = CachedReleases::Stamped {
+        fetched_at: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs(),
+        releases: releases.to_vec(),
+    };
+    write_atomically(path, serde_json::to_string(&cached)?)?;
+    set_mtime(path, epoch)
+}
+
+/// Fetch the releases list from `url` into `releases_json_path`, falling back to what is already
+/// there when the API fails or takes longer than `timeout`. A cached list that can't be read
+/// doesn't count.
+pub(crate) fn fetch_releases(
+    client: &HttpClient,
+    url: &str,
+    timeout: std::time::Duration,
+    releases_json_path: &Path,
+) -> Result<Vec<Release>, Error> {
+    let cached = || {
+        read_cached_releases(releases_json_path)
+            .ok()
+            .map(|(releases, _)| releases)
+    };
+    let releases = match send(client.get(url)?.timeout(timeout)) {
+        Ok(releases) => releases,
+        Err(e) => {
+            let Some(releases) = cached() else {
+                return Err(e.into());
+            };
+            let why = if e.is_timeout() {
+                format!("did not answer within {}s", timeout.as_secs())
+            } else {
+                format!("is unreachable ({e})")
+            };
+            warn!("{url} {why}, using the cached releases list, which may be out of date");
+            return Ok(releases);
+        }
+    };
+
+    if let Some(reset) = rate_limit_reset(&releases) {
+        if let Some(releases) = cached() {
+            warn!("the GitHub API rate limit is used up until {reset}, using the cached releases list, which may be out of date");
+            return Ok(releases);
+        }
+        return Err(anyhow!(
+            "the GitHub API rate limit is used up until {reset} and there is no cached releases list. Try again then, or pin buck2_version to a release."
+        ));
+    }
+
+    if releases.status().is_success() {
+        let releases: Vec<Release> = serde_json::from_str(&releases.text_with_charset("utf-8")?)?;
+        write_cached_releases(releases_json_path, &releases)?;
+        Ok(releases)
+    } else if let Some(releases) = cached() {
+        // maybe out of date, but not that bad
+        Ok(releases)
+    } else {
+        Err(anyhow!("No releases.json"))
+    }
+}
+
+/// Find the release tagged `tag`.
+///
+/// An exact tag is looked up on its own first. Otherwise, and for `latest`, the releases list is
+/// searched: its first page only has the newest releases, which is all `latest` needs. Only when
+/// `tag` isn't among them are the remaining pages fetched, and everything fetched is cached in
+/// `releases.json` for next time.
+pub(crate) fn find_release(
+    config: &BuckleConfig,
+    path: &Path,
+    tag: &str,
+) -> Result<Option<Release>, Error> {
+    // An exact tag other than `latest` can be asked for directly, without the list.
+    if tag != "latest" {
+        if let Some(release) = get_release_by_tag(config, tag)? {
+            return Ok(Some(release));
+        }
+        if config.strict_version_match {
+            return Ok(None);
+        }
+        // The tag may only differ by a `v` prefix from what was asked for.
+        if let Some(alternative) = toggle_v_prefix(tag) {
+            if let Some(release) = get_release_by_tag(config, &alternative)? {
+                return Ok(Some(release));
+            }
+        }
+        // Or the version is only in the release's name, which takes going through the list.
+    }
+
+    let matches = |release: &Release| {
+        release_eligible(config, release)
+            && release_matches(release, tag, config.strict_version_match)
+    };
+    let releases = get_releases(config, path)?;
+    // buck2 marks its rolling `latest` release as a prerelease, so only drafts, whose assets may
+    // be incomplete, are skipped for an exact tag.
+    if let Some(release) = releases
+        .iter()
+        .find(|release| release.tag_name == tag && (config.include_draft || !release.draft))
+        .or_else(|| releases.iter().find(|release| matches(release)))
+    {
+        return Ok(Some(release.clone()));
+    }
+    // A local releases list has no further pages.
+    if local_releases_path(config).is_some() {
+        return Ok(None);
+    }
+
+    let client = http_client(config)?;
+    let (releases, found) = paginate_releases(&releases_list_url(config), matches, |url| {
+        let resp = send(client.get(url)?)?;
+        if !resp.status().is_success() {
+            return Err(anyhow!("fetching {url} failed ({})", resp.status()));
+        }
+        let next = resp
+            .headers()
+            .get(reqwest::header::LINK)
+            .and_then(|link| link.to_str().ok())
+            .and_then(next_page_url);
+        Ok((resp.json()?, next))
+    })?;
+    write_cached_releases(&path.join("releases.json"), &releases)?;
+    Ok(found)
+}
+
+/// Whether `release` is the one `version` asks for.
+///
+/// Upstreams disagree on whether tags carry a `v` prefix, and some put the plain version in the
+/// release name instead, so unless `strict` both of those match too: `7.0.0` finds a release
+/// tagged `v7.0.0` or named `7.0.0`.
+pub(crate) fn release_matches(release: &Release, version: &str, strict: bool) -> bool {
+    if release.tag_name == version {
+        return true;
+    }
+    if strict {
+        return false;
+    }
+    let version = version.strip_prefix('v').unwrap_or(version);
+    [Some(&release.tag_name), release.name.as_ref()]
+        .into_iter()
+        .flatten()
+        .any(|candidate| candidate.strip_prefix('v').unwrap_or(candidate) == version)
+}
+
+/// Whether a version constraint, or a version only matched by release name, may resolve to
+/// `release`. Exact tags are used whatever their release is marked as.
+pub(crate) fn release_eligible(config: &BuckleConfig, release: &Release) -> bool {
+    (config.include_draft || !release.draft) && (config.include_prerelease || !release.prerelease)
+}
+
+/// `tag` with a leading `v` added or removed, if it looks like a version at all.
+pub(crate) fn toggle_v_prefix(tag: &str) -> Option<String> {
+    match tag.strip_prefix('v') {
+        Some(rest) => rest
+            .starts_with(|c: char| c.is_ascii_digit())
+            .then(|| rest.to_owned()),
+        None => tag
+            .starts_with(|c: char| c.is_ascii_digit())
+            .then(|| format!("v{tag}")),
+    }
+}
+
+/// Fetch the single release tagged `tag`, or `None` if there is no such release.
+pub(crate) fn get_release_by_tag(
+    config: &BuckleConfig,
+    tag: &str,
+) -> Result<Option<Release>, Error> {
+    if let Some(local) = local_releases_path(config) {
+        return Ok(read_local_releases(&local)?
+            .into_iter()
+            .find(|release| release.tag_name == tag));
+    }
+    let url = release_by_tag_url(&releases_api_url(config), tag);
+    let resp = send(http_client(config)?.get(&url)?)?;
+    if resp.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!("fetching {url} failed ({})", resp.status()));
+    }
+    Ok(Some(resp.json()?))
+}
+
+pub(crate) fn release_by_tag_url(api: &str, tag: &str) -> String {
+    let mut url = format!("{}/tags/", api.trim_end_matches('/'));
+    url.extend(url::form_urlencoded::byte_serialize(tag.as_bytes()));
+    url
+}
+
+/// Fetch pages of releases starting at `url` until one `matches` or there are no more. `fetch`
+/// returns a page and the URL of the next one. Returns every release seen and the match.
+pub(crate) fn paginate_releases(
+    url: &str,
+    matches: impl Fn(&Release) -> bool,
+    mut fetch: impl FnMut(&str) -> Result<(Vec<Release>, Option<String>), Error>,
+) -> Result<(Vec<Release>, Option<Release>), Error> {
+    let mut releases = vec![];
+    let mut next = Some(url.to_owned());
+    while let Some(url) = next {
+        let (page, next_url) = fetch(&url)?;
+        let found = page.iter().find(|release| matches(release)).cloned();
+        releases.extend(page);
+        if found.is_some() {
+            return Ok((releases, found));
+        }
+        next = next_url;
+    }
+    Ok((releases, None))
+}
+
+/// The `rel="next"` target of a `Link` header, e.g.
+/// `<https://api.github.com/...?page=2>; rel="next", <https://api.github.com/...?page=5>; rel="last"`.
+pub(crate) fn next_page_url(link: &str) -> Option<String> {
+    link.split(',').find_map(|entry| {
+        let (target, params) = entry.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == "rel=\"next\"")
+            .then(|| {
+                target
+                    .trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_owned()
+            })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fetch_releases_falls_back_on_timeout() {
+        use std::time::Duration;
+        // Accepts connections but never answers.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/releases", listener.local_addr().unwrap());
+        let client = HttpClient {
+            inner: reqwest::blocking::Client::new(),
+            allowed_hosts: None,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let releases_json_path = dir.path().join("releases.json");
+
+        assert!(fetch_releases(
+            &client,
+            &url,
+            Duration::from_millis(200),
+            &releases_json_path
+        )
+        .is_err());
+
+        let cached = vec![release("2023-07-15")];
+        fs::write(&releases_json_path, serde_json::to_string(&cached).unwrap()).unwrap();
+        let started = std::time::Instant::now();
+        let releases = fetch_releases(
+            &client,
+            &url,
+            Duration::from_millis(200),
+            &releases_json_path,
+        )
+        .unwrap();
+        assert_eq!(releases, cached);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(listener);
+    }
+
+    #[test]
+    fn test_write_cached_releases_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("releases.json");
+        write_cached_releases(&path, &[release("2023-07-15")]).unwrap();
+        // What an interrupted write leaves behind: a temporary file, never a partial list.
+        let leftover = dir.path().join(".tmpAbC123");
+        fs::write(&leftover, r#"[{"tag_name": "2023-08"#).unwrap();
+
+        let releases = vec![release("2023-08-01"), release("2023-07-15")];
+        write_cached_releases(&path, &releases).unwrap();
+        assert_eq!(read_cached_releases(&path).unwrap().0, releases);
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".tmpAbC123", "releases.json"]);
+    }
+
+    #[test]
+    fn test_format_utc() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_utc(1_700_000_000), "2023-11-14 22:13:20 UTC");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00 UTC");
+    }
+
+    #[test]
+    fn test_next_page_url() {
+        assert_eq!(
+            next_page_url(
+                "<https://api.github.com/releases?page=2>; rel=\"next\", <https://api.github.com/releases?page=4>; rel=\"last\""
+            )
+            .as_deref(),
+            Some("https://api.github.com/releases?page=2")
+        );
+        assert_eq!(
+            next_page_url(
+                "<https://api.github.com/releases?page=3>; rel=\"prev\", <https://api.github.com/releases?page=1>; rel=\"first\""
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_release_by_tag_url() {
+        assert_eq!(
+            release_by_tag_url(
+                "https://api.github.com/repos/facebook/buck2/releases",
+                "2023-07-15"
+            ),
+            "https://api.github.com/repos/facebook/buck2/releases/tags/2023-07-15"
+        );
+        assert_eq!(
+            release_by_tag_url(
+                "https://ghe.example.com/api/v3/repos/a/b/releases/",
+                "v1.0+fork"
+            ),
+            "https://ghe.example.com/api/v3/repos/a/b/releases/tags/v1.0%2Bfork"
+        );
+    }
+
+    fn release(tag: &str) -> Release {
+        serde_json::from_value(serde_json::json!({
+            "url": "https://api.github.com/r",
+            "html_url": "https://github.com/r",
+            "assets_url": "https://api.github.com/r/assets",
+            "upload_url": "",
+            "id": 1,
+            "node_id": "",
+            "tag_name": tag,
+            "target_commitish": format!("{tag}-commit"),
+            "draft": false,
+            "prerelease": false,
+            "author": {},
+            "assets": [],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_release_matches_v_prefix() {
+        let tagged = release("v7.0.0");
+        assert!(release_matches(&tagged, "v7.0.0", true));
+        assert!(!release_matches(&tagged, "7.0.0", true));
+        assert!(release_matches(&tagged, "7.0.0", false));
+        assert!(!release_matches(&tagged, "7.0.1", false));
+
+        let untagged = release("7.0.0");
+        assert!(release_matches(&untagged, "v7.0.0", false));
+    }
+
+    #[test]
+    fn test_release_matches_name() {
+        let mut named = release("release-7");
+        named.name = Some("7.0.0".to_owned());
+        assert!(release_matches(&named, "7.0.0", false));
+        assert!(release_matches(&named, "v7.0.0", false));
+        assert!(!release_matches(&named, "7.0.0", true));
+    }
+
+    #[test]
+    fn test_toggle_v_prefix() {
+        assert_eq!(toggle_v_prefix("7.0.0").as_deref(), Some("v7.0.0"));
+        assert_eq!(toggle_v_prefix("v7.0.0").as_deref(), Some("7.0.0"));
+        assert_eq!(
+            toggle_v_prefix("2023-07-15").as_deref(),
+            Some("v2023-07-15")
+        );
+        assert_eq!(toggle_v_prefix("latest"), None);
+        assert_eq!(toggle_v_prefix("vendor"), None);
+    }
+
+    #[test]
+    fn test_paginate_releases() {
+        let pages = |url: &str| -> Result<(Vec<Release>, Option<String>), Error> {
+            Ok(match url {
+                "page1" => (vec![release("c"), release("b")], Some("page2".to_owned())),
+                "page2" => (vec![release("a")], Some("page3".to_owned())),
+                "page3" => (vec![release("old")], None),
+                other => panic!("unexpected page {other}"),
+            })
+        };
+
+        // Stops as soon as the tag turns up.
+        let mut fetched = vec![];
+        let (seen, found) = paginate_releases(
+            "page1",
+            |r| r.tag_name == "a",
+            |url| {
+                fetched.push(url.to_owned());
+                pages(url)
+            },
+        )
+        .unwrap();
+        assert_eq!(found.unwrap().target_commitish, "a-commit");
+        assert_eq!(fetched, ["page1", "page2"]);
+        assert_eq!(seen.len(), 3);
+
+        // Exhausts the list when it never does.
+        let (seen, found) = paginate_releases("page1", |_| false, pages).unwrap();
+        assert!(found.is_none());
+        assert_eq!(seen.len(), 4);
+    }
+}
@@ -0,0 +1,311 @@
+//! Pinning a requested version such as `latest` down to a release to download.
+
+use crate::config::{
+    is_release_date, min_release_date, set_mtime, source_date_epoch, BuckleConfig,
+};
+use crate::error::BuckleError;
+use crate::http::{http_client, send};
+use crate::releases::{find_release, get_releases, release_eligible, toggle_v_prefix};
+use anyhow::{anyhow, Error};
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// A requested version pinned down to something we can download and cache.
+pub struct ResolvedVersion {
+    /// The release to download from `base_download_url`.
+    pub tag: String,
+    /// Where that release is cached.
+    pub dir: PathBuf,
+    /// The `YYYY-MM-DD` date of the release, if known. Dated tags are their own date; `latest`
+    /// uses the date GitHub reports for it.
+    pub date: Option<String>,
+    /// The commit the release was built from, if resolving it looked the release up, as `latest`
+    /// does.
+    pub commit: Option<String>,
+}
+
+/// Whether `version` pins one release by its tag, rather than asking for whichever release
+/// `latest`, `latest-stable` or a `>=` constraint picks.
+pub(crate) fn is_exact_version(version: &str) -> bool {
+    version != "latest" && version != "latest-stable" && !version.starts_with(">=")
+}
+
+/// Work out which release the configured version refers to and where it is cached, without
+/// downloading the binary.
+pub(crate) fn resolve_version(
+    config: &BuckleConfig,
+    output_dir: &Path,
+) -> Result<ResolvedVersion, Error> {
+    let mut buck2_path = output_dir.to_path_buf();
+    let mut tag = config.buck2_version.clone();
+
+    if config.buck2_version == "latest" {
+        // With update checks off, whatever `latest` was last downloaded as stays in use for good.
+        let pin = if config.reresolve {
+            None
+        } else if config.disable_update_check {
+            LatestPin::read(output_dir).filter(|pin| output_dir.join(&pin.commit).exists())
+        } else {
+            config
+                .cache_timeout_secs
+                .and_then(|timeout| LatestPin::read(output_dir).filter(|pin| pin.is_fresh(timeout)))
+        };
+        if let Some(pin) = pin {
+            buck2_path.push(&pin.commit);
+            return Ok(ResolvedVersion {
+                tag,
+                dir: buck2_path,
+                date: pin.date,
+                commit: Some(pin.commit),
+            });
+        }
+
+        // We need to put "latest" releases in a unique directory as they will point to a different
+        // commit over time: get the current latest release commit from the releases page.
+        let Some(release) = find_release(config, output_dir, &tag)? else {
+            return Err(BuckleError::VersionNotFound { version: tag }.into());
+        };
+        // Moving to a new `latest` can break builds, so say so instead of switching silently.
+        if let Some(previous) = LatestPin::read(output_dir) {
+            if previous.commit != release.target_commitish {
+                info!(
+                    "buck2 advanced from {} to {}",
+                    previous.commit, release.target_commitish
+                );
+            }
+        }
+        let date = release
+            .created_at
+            .as_deref()
+            .and_then(|created_at| created_at.get(..10))
+            .filter(|date| is_release_date(date))
+            .map(str::to_owned);
+        LatestPin::new(&release.target_commitish, date.clone())?.write(output_dir)?;
+        buck2_path.push(&release.target_commitish);
+        return Ok(ResolvedVersion {
+            tag,
+            dir: buck2_path,
+            date,
+            commit: Some(release.target_commitish),
+        });
+    } else if config.buck2_version == "latest-stable" {
+        tag = get_latest_stable(config)?;
+        buck2_path.push(&tag);
+    } else if config.buck2_version.starts_with(">=") {
+        // Dates order correctly as strings, so the newest dated release is the one to use if any
+        // satisfies the constraint.
+        let min_date = min_release_date(&config.buck2_version)?;
+        let newest = get_releases(config, output_dir)?
+            .into_iter()
+            .filter(|release| release_eligible(config, release))
+            .map(|release| release.tag_name)
+            .filter(|tag| is_release_date(tag))
+            .max()
+            .filter(|tag| tag.as_str() >= min_date);
+        let Some(newest) = newest else {
+            return Err(BuckleError::VersionNotFound {
+                version: config.buck2_version.clone(),
+            }
+            .into());
+        };
+        tag = newest;
+        buck2_path.push(&tag);
+    } else {
+        // Unless matching is strict, `7.0.0` may be the release tagged `v7.0.0` or named `7.0.0`,
+        // which is downloaded and cached under its own tag. One cached under either spelling is
+        // used without asking, and a release that can't be looked up is downloaded as asked for.
+        // Dated tags are buck2's own, so they are already exact.
+        if !config.strict_version_match && !is_release_date(&tag) && !output_dir.join(&tag).exists()
+        {
+            match toggle_v_prefix(&tag).filter(|alternative| output_dir.join(alternative).exists())
+            {
+                Some(alternative) => tag = alternative,
+                None => {
+                    if let Ok(Some(release)) = find_release(config, output_dir, &tag) {
+                        tag = release.tag_name;
+                    }
+                }
+            }
+        }
+        buck2_path.push(&tag);
+    }
+    Ok(ResolvedVersion {
+        date: Some(tag.clone()).filter(|tag| is_release_date(tag)),
+        tag,
+        dir: buck2_path,
+        commit: None,
+    })
+}
+
+/// Refuse a resolved version outside the project's `min_buck2_version`..`max_buck2_version`
+/// window, before it is downloaded or run. Release dates order correctly as strings.
+pub(crate) fn check_version_window(
+    config: &BuckleConfig,
+    resolved: &ResolvedVersion,
+) -> Result<(), Error> {
+    if config.min_buck2_version.is_none() && config.max_buck2_version.is_none() {
+        return Ok(());
+    }
+    let Some(date) = &resolved.date else {
+        warn!(
+            "buck2 {} has no release date, so it can't be checked against min_buck2_version and max_buck2_version",
+            resolved.tag
+        );
+        return Ok(());
+    };
+    let outside = if let Some(min) = config.min_buck2_version.as_ref().filter(|min| date < *min) {
+        format!("older than min_buck2_version ({min})")
+    } else if let Some(max) = config.max_buck2_version.as_ref().filter(|max| date > *max) {
+        format!("newer than max_buck2_version ({max})")
+    } else {
+        return Ok(());
+    };
+    let released = if &resolved.tag == date {
+        String::new()
+    } else {
+        format!(", released {date},")
+    };
+    Err(anyhow!(
+        "buck2 {}{released} is {outside}, which this project's configuration doesn't support. Pin buck2_version to a release in its range.",
+        resolved.tag
+    ))
+}
+
+/// The commit `latest` last resolved to, kept as `latest.json` in the cache so that
+/// `cache_timeout_secs` can keep using it without looking at the releases list, and so that a
+/// change of `latest` can be pointed out.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LatestPin {
+    pub(crate) commit: String,
+    /// When it was resolved, in seconds since the Unix epoch.
+    pub(crate) resolved_at: u64,
+    /// The date of the release, if GitHub reported one.
+    #[serde(default)]
+    pub(crate) date: Option<String>,
+}
+
+impl LatestPin {
+    pub(crate) const FILE: &'static str = "latest.json";
+
+    pub(crate) fn new(commit: &str, date: Option<String>) -> Result<LatestPin, Error> {
+        Ok(LatestPin {
+            commit: commit.to_owned(),
+            date,
+            resolved_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        })
+    }
+
+    pub(crate) fn read(dir: &Path) -> Option<LatestPin> {
+        let buf = fs::read_to_string(dir.join(Self::FILE)).ok()?;
+        serde_json::from_str(&buf).ok()
+    }
+
+    pub(crate) fn write(&self, dir: &Path) -> Result<(), Error> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(Self::FILE), serde_json::to_string(self)?)?;
+        match source_date_epoch() {
+            Some(epoch) => set_mtime(&dir.join(Self::FILE), epoch),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_fresh(&self, timeout_secs: u64) -> bool {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .is_ok_and(|now| now.as_secs().saturating_sub(self.resolved_at) < timeout_secs)
+    }
+}
+
+/// Resolve `latest-stable` to the newest release listed in the configured stable manifest.
+///
+/// The manifest is plain text with one approved release tag per line. Blank lines and lines
+/// starting with `#` are ignored. Tags are compared as strings, which orders buck2's date-based
+/// tags correctly.
+pub(crate) fn get_latest_stable(config: &BuckleConfig) -> Result<String, Error> {
+    let url = config.stable_manifest_url.as_deref().ok_or(anyhow!(
+        "latest-stable requires a stable_manifest_url to be configured"
+    ))?;
+    let resp = send(http_client(config)?.get(url)?)
+        .map_err(|e| anyhow!("could not fetch the stable manifest from {url}: {e}"))?;
+    if !resp.status().is_success() {
+        return Err(anyhow!(
+            "could not fetch the stable manifest from {url}: {}",
+            resp.status()
+        ));
+    }
+    newest_stable_tag(&resp.text()?).ok_or(anyhow!(
+        "the stable manifest at {url} does not list any releases"
+    ))
+}
+
+pub(crate) fn newest_stable_tag(manifest: &str) -> Option<String> {
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .max()
+        .map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newest_stable_tag() {
+        let manifest = "# vetted by the build team\n2023-07-01\n\n2023-08-01\n  2023-07-15  \n";
+        assert_eq!(newest_stable_tag(manifest), Some("2023-08-01".to_owned()));
+        assert_eq!(newest_stable_tag("# nothing yet\n"), None);
+    }
+
+    #[test]
+    fn test_is_exact_version() {
+        assert!(is_exact_version("2024-09-02"));
+        assert!(is_exact_version("v7.0.0"));
+        assert!(!is_exact_version("latest"));
+        assert!(!is_exact_version("latest-stable"));
+        assert!(!is_exact_version(">=2024-01-01"));
+    }
+
+    #[test]
+    fn test_check_version_window() {
+        let config = BuckleConfig {
+            min_buck2_version: Some("2024-01-01".to_owned()),
+            max_buck2_version: Some("2024-06-30".to_owned()),
+            ..Default::default()
+        };
+        let resolved = |tag: &str, date: Option<&str>| ResolvedVersion {
+            tag: tag.to_owned(),
+            dir: PathBuf::new(),
+            date: date.map(str::to_owned),
+            commit: None,
+        };
+        for date in ["2024-01-01", "2024-03-15", "2024-06-30"] {
+            assert!(check_version_window(&config, &resolved(date, Some(date))).is_ok());
+        }
+        let err = check_version_window(&config, &resolved("2023-12-31", Some("2023-12-31")))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("buck2 2023-12-31 is older than min_buck2_version (2024-01-01)"),
+            "found {err}"
+        );
+        let err = check_version_window(&config, &resolved("latest", Some("2024-07-01")))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(
+                "buck2 latest, released 2024-07-01, is newer than max_buck2_version (2024-06-30)"
+            ),
+            "found {err}"
+        );
+        // Without a date there is nothing to compare.
+        assert!(check_version_window(&config, &resolved("v7.0.0", None)).is_ok());
+    }
+}
@@ -0,0 +1,360 @@
+//! Shared helpers for integration tests that must not touch the network.
+//!
+//! `MockServer` is a tiny HTTP/1.1 server on localhost that mimics the bits of the buck2 release
+//! layout buckle cares about, and `buckle` builds a command with a hermetic environment pointed
+//! at it.
+#![allow(dead_code)]
+
+use assert_cmd::Command;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Version used by tests that don't care which one they get.
+pub const VERSION: &str = "2023-07-15";
+
+/// A shell script standing in for buck2. It echoes its arguments so tests can check forwarding.
+pub const FAKE_BUCK2: &str = "#!/bin/sh\necho \"buck2 fake $*\"\n";
+
+/// The prelude hash served alongside the fake buck2.
+pub const PRELUDE_HASH: &str = "0123456789abcdef0123456789abcdef01234567";
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The query string, without the `?`.
+    pub query: Option<String>,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn ok(body: impl Into<Vec<u8>>) -> Response {
+        Response {
+            status: 200,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn status(status: u16) -> Response {
+        Response {
+            status,
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+}
+
+type Handler = dyn Fn(&Request) -> Response + Send + Sync;
+
+pub struct MockServer {
+    port: u16,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl MockServer {
+    /// Start a server answering every request with `handler`.
+    pub fn start(handler: impl Fn(&Request) -> Response + Send + Sync + 'static) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let handler: Arc<Handler> = Arc::new(handler);
+        let log = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                let log = log.clone();
+                thread::spawn(move || serve(stream, &*handler, &log));
+            }
+        });
+        MockServer { port, requests }
+    }
+
+    /// Start a server with a fixed set of routes; anything else is a 404.
+    pub fn with_routes(routes: HashMap<String, Response>) -> MockServer {
+        MockServer::start(move |req| {
+            routes
+                .get(&req.path)
+                .cloned()
+                .unwrap_or_else(|| Response::status(404))
+        })
+    }
+
+    /// Start a server that serves `FAKE_BUCK2` and `PRELUDE_HASH` for `version`.
+    pub fn buck2(version: &str) -> MockServer {
+        MockServer::with_routes(buck2_routes(version))
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}", self.port)
+    }
+
+    /// All requests received so far, in arrival order.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The paths of all requests received so far.
+    pub fn paths(&self) -> Vec<String> {
+        self.requests().into_iter().map(|r| r.path).collect()
+    }
+}
+
+/// The routes a buck2 release mirror serves for `version`.
+pub fn buck2_routes(version: &str) -> HashMap<String, Response> {
+    buck2_routes_with(version, FAKE_BUCK2)
+}
+
+/// Like `buck2_routes`, with `script` standing in for buck2.
+pub fn buck2_routes_with(version: &str, script: &str) -> HashMap<String, Response> {
+    let mut routes = HashMap::new();
+    routes.insert(
+        format!("/{version}/buck2-{}.zst", host_triple()),
+        Response::ok(zstd::encode_all(script.as_bytes(), 0).unwrap()),
+    );
+    routes.insert(
+        format!("/{version}/prelude_hash"),
+        Response::ok(format!("{PRELUDE_HASH}\n")),
+    );
+    routes
+}
+
+fn serve(stream: TcpStream, handler: &Handler, log: &Mutex<Vec<Request>>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_owned();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path.to_owned(), Some(query.to_owned())),
+        None => (target.to_owned(), None),
+    };
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() || line.trim().is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.push((k.trim().to_owned(), v.trim().to_owned()));
+        }
+    }
+    let request = Request {
+        method,
+        path,
+        query,
+        headers,
+    };
+    log.lock().unwrap().push(request.clone());
+    let response = handler(&request);
+
+    let mut stream = stream;
+    let mut head = format!("HTTP/1.1 {} Mock\r\nConnection: close\r\n", response.status);
+    // Tests may claim a different length to simulate a connection dropping mid-download.
+    if !response
+        .headers
+        .iter()
+        .any(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+    {
+        head.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (k, v) in &response.headers {
+        head.push_str(&format!("{k}: {v}\r\n"));
+    }
+    head.push_str("\r\n");
+    let _ = stream.write_all(head.as_bytes());
+    let _ = stream.write_all(&response.body);
+    let _ = stream.flush();
+}
+
+/// The target triple buckle picks for this host by default.
+pub fn host_triple() -> String {
+    let musl = std::fs::read_dir("/lib")
+        .map(|entries| {
+            entries
+                .flatten()
+                .any(|e| e.file_name().to_string_lossy().starts_with("ld-musl-"))
+        })
+        .unwrap_or(false);
+    let libc = if musl { "musl" } else { "gnu" };
+    match (std::env::consts::ARCH, std::env::consts::OS) {
+        (arch, "linux") => format!("{arch}-unknown-linux-{libc}"),
+        (arch, "macos") => format!("{arch}-apple-darwin"),
+        (arch, os) => panic!("no test triple for {arch}/{os}"),
+    }
+}
+
+/// A `buckle` invocation run from `project` with a clean environment that points at `server`
+/// and caches into `cache`.
+pub fn buckle(project: &Path, cache: &Path, server: &MockServer) -> Command {
+    Command::from_std(buckle_std(project, cache, server))
+}
+
+/// Like `buckle`, as a plain `std::process::Command` for tests that spawn it themselves.
+pub fn buckle_std(project: &Path, cache: &Path, server: &MockServer) -> std::process::Command {
+    let mut cmd = std::process::Command::new(assert_cmd::cargo::cargo_bin("buckle"));
+    cmd.env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .env("HOME", cache)
+        .env("USE_BUCK2_VERSION", VERSION)
+        .env("BUCKLE_DOWNLOAD_URL", server.url())
+        .env("BUCKLE_GITHUB_API", server.url())
+        .env("BUCKLE_CACHE", cache)
+        .current_dir(project);
+    cmd
+}
+
+/// A GitHub releases API entry for `tag` at `commit`, with just enough fields for buckle.
+pub fn release_json(tag: &str, commit: &str) -> serde_json::Value {
+    let url = format!("https://api.github.com/repos/facebook/buck2/releases/{tag}");
+    serde_json::json!({
+        "url": url,
+        "html_url": format!("https://github.com/facebook/buck2/releases/tag/{tag}"),
+        "assets_url": format!("{url}/assets"),
+        "upload_url": format!("{url}/assets{{?name,label}}"),
+        "tarball_url": null,
+        "zipball_url": null,
+        "id": 1,
+        "node_id": "RE_1",
+        "tag_name": tag,
+        "target_commitish": commit,
+        "name": tag,
+        "body": null,
+        "draft": false,
+        "prerelease": true,
+        "created_at": null,
+        "published_at": null,
+        "author": {},
+        "assets": [],
+    })
+}
+
+/// The path buckle fetches the buck2 releases list from, below the GitHub API base.
+pub const RELEASES_PATH: &str = "/repos/facebook/buck2/releases";
+
+/// Seed the cached releases list in `cache`, so buckle treats it as freshly fetched.
+pub fn seed_releases(cache: &Path, releases: &[serde_json::Value]) {
+    let buckle_dir = cache.join("buckle");
+    std::fs::create_dir_all(&buckle_dir).unwrap();
+    std::fs::write(
+        buckle_dir.join("releases.json"),
+        serde_json::to_string(releases).unwrap(),
+    )
+    .unwrap();
+}
+
+/// Write `.buckleconfig.toml` into `project`.
+pub fn write_buckleconfig(project: &Path, contents: &str) {
+    std::fs::write(project.join(".buckleconfig.toml"), contents).unwrap();
+}
+
+/// Run `git` in `dir` with a throwaway identity, failing the test if it fails.
+pub fn git(dir: &Path, args: &[&str]) {
+    let status = std::process::Command::new("git")
+        .args([
+            "-c",
+            "user.name=buckle",
+            "-c",
+            "user.email=buckle@example.com",
+            "-c",
+            "protocol.file.allow=always",
+            "-c",
+            "init.defaultBranch=main",
+        ])
+        .args(args)
+        .current_dir(dir)
+        .env_remove("GIT_DIR")
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "git {args:?} failed");
+}
+
+/// A buck2 project set up by `prelude_project`.
+pub struct PreludeProject {
+    /// The commit every prelude submodule is at.
+    pub head: String,
+    /// The repo the submodules were cloned from, removed when this is dropped.
+    pub upstream: tempfile::TempDir,
+}
+
+/// Turn `project` into a git repo with a buck2 project whose `prelude` cell is a submodule.
+///
+/// The submodule is checked out at a fresh commit, so it never matches `PRELUDE_HASH`. Keep the
+/// result alive for as long as the project is used.
+pub fn prelude_project(project: &Path) -> PreludeProject {
+    prelude_project_with(project, &["prelude"])
+}
+
+/// Like `prelude_project`, with a submodule at each of `preludes`. The `.buckconfig` is left to the
+/// caller.
+pub fn prelude_project_with(project: &Path, preludes: &[&str]) -> PreludeProject {
+    let upstream_dir = tempfile::tempdir().unwrap();
+    let upstream = upstream_dir.path();
+    git(upstream, &["init", "-q"]);
+    std::fs::write(upstream.join("prelude.bzl"), "# prelude\n").unwrap();
+    git(upstream, &["add", "."]);
+    git(upstream, &["commit", "-q", "-m", "prelude"]);
+    let head = std::process::Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .current_dir(upstream)
+        .output()
+        .unwrap();
+    let head = String::from_utf8(head.stdout).unwrap().trim().to_owned();
+
+    git(project, &["init", "-q"]);
+    for prelude in preludes {
+        git(
+            project,
+            &[
+                "submodule",
+                "add",
+                "-q",
+                upstream.to_str().unwrap(),
+                prelude,
+            ],
+        );
+    }
+    if preludes == ["prelude"] {
+        std::fs::write(
+            project.join(".buckconfig"),
+            "[cells]\nroot = .\nprelude = prelude\n",
+        )
+        .unwrap();
+    }
+    git(project, &["add", "."]);
+    git(project, &["commit", "-q", "-m", "project"]);
+    PreludeProject {
+        head,
+        upstream: upstream_dir,
+    }
+}
@@ -0,0 +1,900 @@
+//! Integration tests for buckle's own `--buckle-*` commands, run against a local mock mirror.
+#![cfg(unix)]
+
+mod common;
+
+use common::{buckle, write_buckleconfig, MockServer, VERSION};
+
+fn stdout(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stdout).to_string()
+}
+
+/// A healthy setup passes the self-check and reports what it resolved.
+#[test]
+fn test_self_check_healthy() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(project.path(), "check_prelude = false\n");
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-self-check")
+        .assert()
+        .success();
+    let out = stdout(&assert);
+    assert!(out.contains(".buckleconfig.toml"), "found {out}");
+    assert!(
+        out.contains(&format!("resolved version: {VERSION}")),
+        "found {out}"
+    );
+    assert!(
+        out.contains("cached binary: not yet downloaded"),
+        "found {out}"
+    );
+    assert!(out.contains("everything looks healthy"), "found {out}");
+    // The self-check must not download or run buck2.
+    assert!(!out.contains("buck2 fake"), "found {out}");
+}
+
+/// `--buckle-version` prints buckle's own version, even when nothing else would work.
+#[test]
+fn test_buckle_version() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(project.path(), "not valid toml\n");
+
+    buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-version")
+        .assert()
+        .success()
+        .stdout(format!("buckle {}\n", env!("CARGO_PKG_VERSION")));
+    assert!(server.paths().is_empty(), "found {:?}", server.paths());
+    assert!(!cache.path().join("buckle").exists());
+}
+
+/// An unusable cache dir fails the self-check with a summary of the problem.
+#[test]
+fn test_self_check_unwritable_cache() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let not_a_dir = cache.path().join("file");
+    std::fs::write(&not_a_dir, "").unwrap();
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_CACHE", &not_a_dir)
+        .arg("--buckle-self-check")
+        .assert()
+        .code(69);
+    let out = stdout(&assert);
+    assert!(out.contains("1 problem(s) found"), "found {out}");
+    assert!(out.contains("is not writable"), "found {out}");
+}
+
+/// `--buckle-which` prints a stable path that links to the active version.
+#[test]
+fn test_which_points_at_current_version() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-which")
+        .assert()
+        .success();
+    let current = cache.path().join("buckle").join("current");
+    assert_eq!(
+        stdout(&assert).trim(),
+        current.join("buck2").display().to_string()
+    );
+    assert_eq!(
+        std::fs::read_link(&current).unwrap(),
+        std::path::Path::new(VERSION)
+    );
+
+    // The stable path can run buck2 directly.
+    let output = std::process::Command::new(current.join("buck2"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("buck2 fake"));
+}
+
+/// `--buckle-info` prints the resolved state as JSON, reporting cache hits on later runs.
+#[test]
+fn test_info_json() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    // The prelude hash is only downloaded while the prelude check is on.
+    write_buckleconfig(project.path(), "check_prelude = true\n");
+
+    let run_info = |expect_hit: bool| {
+        let assert = buckle(project.path(), cache.path(), &server)
+            .arg("--buckle-info")
+            .assert()
+            .success();
+        let info: serde_json::Value = serde_json::from_str(&stdout(&assert)).unwrap();
+        assert_eq!(info["cache_hit"], expect_hit, "{info}");
+        info
+    };
+
+    let info = run_info(false);
+    assert_eq!(info["requested_version"], VERSION);
+    assert_eq!(info["resolved_version"], VERSION);
+    assert_eq!(info["target"], common::host_triple());
+    assert_eq!(info["prelude_hash"], common::PRELUDE_HASH);
+    let buckle_dir = cache.path().join("buckle");
+    assert_eq!(
+        info["binary"],
+        buckle_dir.join(VERSION).join("buck2").display().to_string()
+    );
+    assert_eq!(info["cache_dir"], buckle_dir.display().to_string());
+    assert_eq!(
+        info["config_file"],
+        project
+            .path()
+            .join(".buckleconfig.toml")
+            .display()
+            .to_string()
+    );
+
+    run_info(true);
+}
+
+/// `--buckle-download` populates the cache without running buck2.
+#[test]
+fn test_download_only() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-download")
+        .assert()
+        .success();
+    assert!(stdout(&assert).is_empty(), "found {}", stdout(&assert));
+    assert!(cache
+        .path()
+        .join("buckle")
+        .join(VERSION)
+        .join("buck2")
+        .exists());
+
+    // Running buck2 afterwards needs no further downloads.
+    let requests = server.requests().len();
+    buckle(project.path(), cache.path(), &server)
+        .arg("--version")
+        .assert()
+        .success();
+    assert_eq!(server.requests().len(), requests);
+}
+
+/// `--buckle-print-config` shows the effective config, including environment overrides.
+#[test]
+fn test_print_config() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(
+        project.path(),
+        "check_prelude = true\nprelude_section = \"repositories\"\n",
+    );
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_PRELUDE_CHECK", "NO")
+        .arg("--buckle-print-config")
+        .assert()
+        .success();
+    let out = stdout(&assert);
+    let config: toml::Table = toml::from_str(&out).unwrap();
+    assert_eq!(
+        config["check_prelude"].as_bool(),
+        Some(false),
+        "found {out}"
+    );
+    assert_eq!(config["buck2_version"].as_str(), Some(VERSION));
+    assert_eq!(
+        config["base_download_url"].as_str(),
+        Some(server.url().as_str())
+    );
+    assert_eq!(config["prelude_section"].as_str(), Some("repositories"));
+    // Printing the config must not download anything.
+    assert!(server.requests().is_empty());
+}
+
+/// `--buckle-verbose` shows the exact command before running it, without passing the flag on.
+#[test]
+fn test_verbose_prints_command() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(project.path(), "check_prelude = false\n");
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-verbose", "build", "//:two words"])
+        .assert()
+        .success()
+        .stdout("buck2 fake build //:two words\n");
+    let err = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    let binary = cache.path().join("buckle").join(VERSION).join("buck2");
+    assert!(
+        err.contains(&format!(
+            "buckle: running {} build '//:two words' (",
+            binary.display()
+        )),
+        "found {err}"
+    );
+    assert!(err.contains("environment variables)"), "found {err}");
+
+    // The environment variable does the same.
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_VERBOSE", "1")
+        .arg("--version")
+        .assert()
+        .success();
+    let err = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(err.contains("buckle: running "), "found {err}");
+}
+
+/// `BUCKLE_INTERNAL` runs buckle commands for wrappers that can't add arguments, and unknown
+/// buckle options are rejected rather than handed to buck2.
+#[test]
+fn test_buckle_internal_and_unknown_options() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_INTERNAL", "which")
+        .assert()
+        .success();
+    assert!(stdout(&assert).trim().ends_with("current/buck2"));
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-wich", "build"])
+        .assert()
+        .failure();
+    let err = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(
+        err.contains("unknown buckle option --buckle-wich"),
+        "found {err}"
+    );
+
+    // After `--`, it is buck2's.
+    buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-no-prelude-check", "--", "--buckle-wich"])
+        .assert()
+        .success()
+        .stdout("buck2 fake --buckle-wich\n");
+}
+
+/// The buckle directory `--buckle-print-config` reports for `cmd`.
+fn buckle_dir(cmd: &mut assert_cmd::Command) -> String {
+    let assert = cmd.arg("--buckle-print-config").assert().success();
+    let config: toml::Table = toml::from_str(&stdout(&assert)).unwrap();
+    config["buckle_dir"].as_str().unwrap().to_owned()
+}
+
+/// `BUCKLE_CACHE` beats `BUCKLE_HOME`, which beats `cache_dir`, which beats the OS default.
+#[test]
+fn test_cache_location_precedence() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let home = tempfile::tempdir().unwrap();
+    let configured = tempfile::tempdir().unwrap();
+    write_buckleconfig(
+        project.path(),
+        &format!("cache_dir = \"{}\"\n", configured.path().display()),
+    );
+    let path = |dir: &std::path::Path| dir.display().to_string();
+
+    assert_eq!(
+        buckle_dir(buckle(project.path(), cache.path(), &server).env("BUCKLE_HOME", home.path())),
+        path(&cache.path().join("buckle"))
+    );
+    assert_eq!(
+        buckle_dir(
+            buckle(project.path(), cache.path(), &server)
+                .env_remove("BUCKLE_CACHE")
+                .env("BUCKLE_HOME", home.path())
+        ),
+        path(home.path())
+    );
+    assert_eq!(
+        buckle_dir(buckle(project.path(), cache.path(), &server).env_remove("BUCKLE_CACHE")),
+        path(&configured.path().join("buckle"))
+    );
+
+    // The OS default only follows XDG on Linux.
+    if !cfg!(target_os = "linux") {
+        return;
+    }
+    let bare = tempfile::tempdir().unwrap();
+    assert_eq!(
+        buckle_dir(
+            buckle(bare.path(), cache.path(), &server)
+                .env_remove("BUCKLE_CACHE")
+                .env("XDG_CACHE_HOME", cache.path().join("xdg"))
+        ),
+        path(&cache.path().join("xdg").join("buckle"))
+    );
+}
+
+/// Everything buckle keeps goes under `BUCKLE_HOME`.
+#[test]
+fn test_buckle_home_holds_all_state() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let home = tempfile::tempdir().unwrap();
+
+    buckle(project.path(), cache.path(), &server)
+        .env_remove("BUCKLE_CACHE")
+        .env("BUCKLE_HOME", home.path())
+        .arg("--version")
+        .assert()
+        .success();
+    assert!(home.path().join(VERSION).join("buck2").exists());
+    assert!(home.path().join("current").exists());
+    assert!(!cache.path().join("buckle").exists());
+}
+
+/// buckle's own failures use a reserved exit code, while buck2's exit code passes through.
+#[test]
+fn test_exit_codes() {
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    let server = MockServer::buck2(VERSION);
+    write_buckleconfig(project.path(), "check_prelude = \"sometimes\"\n");
+    buckle(project.path(), cache.path(), &server)
+        .arg("--version")
+        .assert()
+        .code(69);
+
+    let server = MockServer::with_routes(common::buck2_routes_with(VERSION, "#!/bin/sh\nexit 3\n"));
+    write_buckleconfig(project.path(), "check_prelude = false\n");
+    buckle(project.path(), cache.path(), &server)
+        .arg("build")
+        .assert()
+        .code(3);
+}
+
+/// With `BUCKLE_EXEC_MODE=exec`, buck2 replaces buckle, so its parent is whoever started buckle.
+#[test]
+fn test_exec_mode_replaces_buckle() {
+    let server = MockServer::with_routes(common::buck2_routes_with(
+        VERSION,
+        "#!/bin/sh\necho $PPID\nexit 3\n",
+    ));
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(project.path(), "check_prelude = false\n");
+    let parent = std::process::id().to_string();
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_EXEC_MODE", "exec")
+        .arg("build")
+        .assert()
+        .code(3);
+    assert_eq!(stdout(&assert).trim(), parent);
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_EXEC_MODE", "spawn")
+        .arg("build")
+        .assert()
+        .code(3);
+    assert_ne!(stdout(&assert).trim(), parent);
+
+    buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_EXEC_MODE", "fork")
+        .arg("build")
+        .assert()
+        .code(69);
+}
+
+/// With `versioned_bin`, `bin/buck2-<version>` is a hard link to the cached binary.
+#[test]
+fn test_versioned_bin_links_binary() {
+    use std::os::unix::fs::MetadataExt;
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(
+        project.path(),
+        "check_prelude = false\nversioned_bin = true\n",
+    );
+
+    for _ in 0..2 {
+        buckle(project.path(), cache.path(), &server)
+            .arg("--version")
+            .assert()
+            .success()
+            .stdout("buck2 fake --version\n");
+    }
+    let buckle_dir = cache.path().join("buckle");
+    let link = std::fs::metadata(buckle_dir.join("bin").join(format!("buck2-{VERSION}"))).unwrap();
+    let binary = std::fs::metadata(buckle_dir.join(VERSION).join("buck2")).unwrap();
+    assert_eq!(link.ino(), binary.ino());
+    assert_eq!(link.dev(), binary.dev());
+}
+
+/// `.buckle-version` pins the version below `USE_BUCK2_VERSION` and above `.buckleconfig.toml`.
+#[test]
+fn test_buckle_version_file() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let subdir = project.path().join("sub");
+    std::fs::create_dir(&subdir).unwrap();
+    write_buckleconfig(project.path(), "buck2_version = \"2022-01-01\"\n");
+    let version = |dir: &std::path::Path| {
+        let assert = buckle(dir, cache.path(), &server)
+            .env_remove("USE_BUCK2_VERSION")
+            .arg("--buckle-print-config")
+            .assert()
+            .success();
+        let config: toml::Table = toml::from_str(&stdout(&assert)).unwrap();
+        config["buck2_version"].as_str().unwrap().to_owned()
+    };
+
+    std::fs::write(
+        project.path().join(".buckle-version"),
+        format!("# pinned for the release branch\n{VERSION}\n"),
+    )
+    .unwrap();
+    assert_eq!(version(&subdir), VERSION);
+
+    std::fs::write(subdir.join(".buckle-version"), "latest\n").unwrap();
+    assert_eq!(version(&subdir), "latest");
+    assert_eq!(version(project.path()), VERSION);
+
+    std::fs::write(subdir.join(".buckle-version"), ">=2023-07-01\n").unwrap();
+    assert_eq!(version(&subdir), ">=2023-07-01");
+
+    let assert = buckle(&subdir, cache.path(), &server)
+        .env("USE_BUCK2_VERSION", "2021-01-01")
+        .arg("--buckle-print-config")
+        .assert()
+        .success();
+    assert!(stdout(&assert).contains("buck2_version = \"2021-01-01\""));
+
+    std::fs::write(subdir.join(".buckle-version"), "<2023-07-01\n").unwrap();
+    let assert = buckle(&subdir, cache.path(), &server)
+        .env_remove("USE_BUCK2_VERSION")
+        .arg("--buckle-print-config")
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(
+        stderr.contains("unsupported buck2 version constraint '<2023-07-01'"),
+        "found {stderr}"
+    );
+}
+
+/// The version file closest to the working directory wins, whatever its kind.
+#[test]
+fn test_nearest_version_file_wins() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let child = project.path().join("child");
+    std::fs::create_dir(&child).unwrap();
+    std::fs::write(project.path().join(".buckle-version"), "2022-01-01\n").unwrap();
+    write_buckleconfig(&child, &format!("buck2_version = \"{VERSION}\"\n"));
+    let version = |dir: &std::path::Path| {
+        let assert = buckle(dir, cache.path(), &server)
+            .env_remove("USE_BUCK2_VERSION")
+            .arg("--buckle-print-config")
+            .assert()
+            .success();
+        let config: toml::Table = toml::from_str(&stdout(&assert)).unwrap();
+        config["buck2_version"].as_str().unwrap().to_owned()
+    };
+
+    assert_eq!(version(&child), VERSION);
+    assert_eq!(version(project.path()), "2022-01-01");
+
+    // Within one directory, `.buckle-version` comes first.
+    std::fs::write(child.join(".buckle-version"), "latest\n").unwrap();
+    assert_eq!(version(&child), "latest");
+}
+
+/// A `buck2` entry in asdf's `.tool-versions` is used when nothing buckle-specific sets a version.
+#[test]
+fn test_tool_versions_file() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let subdir = project.path().join("sub");
+    std::fs::create_dir(&subdir).unwrap();
+    let version = |dir: &std::path::Path| {
+        let assert = buckle(dir, cache.path(), &server)
+            .env_remove("USE_BUCK2_VERSION")
+            .arg("--buckle-print-config")
+            .assert()
+            .success();
+        let config: toml::Table = toml::from_str(&stdout(&assert)).unwrap();
+        config["buck2_version"].as_str().unwrap().to_owned()
+    };
+
+    std::fs::write(
+        project.path().join(".tool-versions"),
+        format!("nodejs 20.1.0\n# build tooling\nbuck2   {VERSION} 2023-06-01  # pinned\npython 3.11.4\n"),
+    )
+    .unwrap();
+    // A closer file without a buck2 entry doesn't hide it.
+    std::fs::write(subdir.join(".tool-versions"), "rust 1.72.0\n").unwrap();
+    assert_eq!(version(&subdir), VERSION);
+
+    // buckle's own files win.
+    std::fs::write(subdir.join(".buckle-version"), "latest\n").unwrap();
+    assert_eq!(version(&subdir), "latest");
+    write_buckleconfig(project.path(), "buck2_version = \"2022-01-01\"\n");
+    assert_eq!(version(project.path()), "2022-01-01");
+}
+
+/// buck2 sees `buck2` as its program name unless `real_arg0` asks for the cached binary's path.
+#[test]
+fn test_buck2_arg0() {
+    // A shell script's `$0` is its path whatever buckle passes, but a copy of the shell itself
+    // reports the name it was started under.
+    let mut routes = common::buck2_routes(VERSION);
+    routes.insert(
+        format!("/{VERSION}/buck2-{}.zst", common::host_triple()),
+        common::Response::ok(
+            zstd::encode_all(std::fs::read("/bin/sh").unwrap().as_slice(), 0).unwrap(),
+        ),
+    );
+    let server = MockServer::with_routes(routes);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+
+    buckle(project.path(), cache.path(), &server)
+        .args(["-c", "echo \"$0\""])
+        .assert()
+        .success()
+        .stdout("buck2\n");
+
+    let binary = cache.path().join("buckle").join(VERSION).join("buck2");
+    buckle(project.path(), cache.path(), &server)
+        .env("BUCKLE_REAL_ARG0", "1")
+        .args(["-c", "echo \"$0\""])
+        .assert()
+        .success()
+        .stdout(format!("{}\n", binary.display()));
+}
+
+/// `exec_wrapper` runs buck2 under the wrapper, whose exit code buckle passes on.
+#[test]
+fn test_exec_wrapper() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    // Reports its first argument, runs the rest and fails on its own account.
+    let wrapper = "#!/bin/sh\nmode=$1; shift\necho \"wrapper $mode\"\n\"$@\"\nexit 3\n";
+    std::fs::write(project.path().join("wrapper.sh"), wrapper).unwrap();
+    write_buckleconfig(
+        project.path(),
+        &format!(
+            "check_prelude = false\nexec_wrapper = [\"sh\", \"{}\", \"timed\"]\n",
+            project.path().join("wrapper.sh").display()
+        ),
+    );
+
+    let binary = cache.path().join("buckle").join(VERSION).join("buck2");
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-verbose", "build", "//..."])
+        .assert()
+        .code(3)
+        .stdout("wrapper timed\nbuck2 fake build //...\n");
+    let err = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(
+        err.contains(&format!(
+            "running sh {} timed {} build //...",
+            project.path().join("wrapper.sh").display(),
+            binary.display()
+        )),
+        "found {err}"
+    );
+}
+
+/// A dotenv file's variables reach buck2, below `[env]` and above the inherited environment.
+#[test]
+fn test_env_file() {
+    let server = MockServer::with_routes(common::buck2_routes_with(
+        VERSION,
+        "#!/bin/sh\necho \"$FROM_FILE|$QUOTED|$OVERRIDDEN|$INHERITED\"\n",
+    ));
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    std::fs::write(
+        project.path().join(".env"),
+        "# for buck2\nFROM_FILE=file\nQUOTED=\"two words\" # and a comment\nOVERRIDDEN=file\nINHERITED=file\n",
+    )
+    .unwrap();
+    write_buckleconfig(
+        project.path(),
+        "check_prelude = false\n[env]\nOVERRIDDEN = \"config\"\n",
+    );
+
+    buckle(project.path(), cache.path(), &server)
+        .env("INHERITED", "shell")
+        .arg("--buckle-env-file=.env")
+        .assert()
+        .success()
+        .stdout("file|two words|config|file\n");
+
+    // The same file from `env_file`, relative to `.buckleconfig.toml`, even from a subdirectory.
+    let subdir = project.path().join("sub");
+    std::fs::create_dir(&subdir).unwrap();
+    write_buckleconfig(
+        project.path(),
+        "check_prelude = false\nenv_file = \".env\"\n",
+    );
+    buckle(&subdir, cache.path(), &server)
+        .assert()
+        .success()
+        .stdout("file|two words|file|file\n");
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-env-file=missing.env")
+        .assert()
+        .failure();
+    let err = String::from_utf8_lossy(&assert.get_output().stderr).to_string();
+    assert!(
+        err.contains("failed to read env file missing.env"),
+        "found {err}"
+    );
+}
+
+/// A deleted working directory is reported as an error rather than a panic.
+#[test]
+fn test_deleted_working_directory() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let gone = project.path().join("gone");
+    std::fs::create_dir(&gone).unwrap();
+
+    // Only a shell can remove the directory it is in before starting buckle there.
+    let output = std::process::Command::new("sh")
+        .args([
+            "-c",
+            "cd \"$GONE\" && rmdir \"$GONE\" && exec \"$BUCKLE\" --version",
+        ])
+        .env("BUCKLE", assert_cmd::cargo::cargo_bin("buckle"))
+        .env("GONE", &gone)
+        .env("BUCKLE_CACHE", cache.path())
+        .env("BUCKLE_DOWNLOAD_URL", server.url())
+        .env("USE_BUCK2_VERSION", VERSION)
+        .output()
+        .unwrap();
+    let err = String::from_utf8_lossy(&output.stderr).to_string();
+    assert_eq!(output.status.code(), Some(69), "found {err}");
+    assert!(
+        err.contains("could not determine the current directory"),
+        "found {err}"
+    );
+    assert!(!err.contains("panicked"), "found {err}");
+}
+
+/// `default_args` reach buck2 ahead of the user's arguments, after buckle took its own.
+#[test]
+fn test_default_args_prepended() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    write_buckleconfig(
+        project.path(),
+        "check_prelude = false\ndefault_args = [\"--isolation-dir\", \"ci\"]\n",
+    );
+
+    buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-quiet", "build", "//:target"])
+        .assert()
+        .success()
+        .stdout("buck2 fake --isolation-dir ci build //:target\n");
+
+    // The user's own flag comes last, so it wins where buck2 lets a flag repeat.
+    buckle(project.path(), cache.path(), &server)
+        .args(["--isolation-dir", "mine", "build"])
+        .assert()
+        .success()
+        .stdout("buck2 fake --isolation-dir ci --isolation-dir mine build\n");
+
+    // Nothing is prepended for buckle's own commands.
+    buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-which")
+        .assert()
+        .success()
+        .stdout(format!(
+            "{}\n",
+            cache
+                .path()
+                .join("buckle")
+                .join("current")
+                .join("buck2")
+                .display()
+        ));
+}
+
+/// A cache of `versions`, each a version dir holding a `size` byte buck2 last used `days_ago`,
+/// with `current` pointing at the first.
+fn synthetic_cache(cache: &std::path::Path, versions: &[(&str, usize, u64)]) {
+    use std::time::{Duration, SystemTime};
+    let buckle_dir = cache.join("buckle");
+    for (version, size, days_ago) in versions {
+        let dir = buckle_dir.join(version);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("buck2"), vec![0; *size]).unwrap();
+        let used = SystemTime::now() - Duration::from_secs(days_ago * 24 * 60 * 60);
+        std::fs::File::open(&dir)
+            .unwrap()
+            .set_modified(used)
+            .unwrap();
+    }
+    std::os::unix::fs::symlink(versions[0].0, buckle_dir.join("current")).unwrap();
+}
+
+fn stderr(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stderr).to_string()
+}
+
+/// `--buckle-gc --older-than` removes versions unused for longer, but never the current one.
+#[test]
+fn test_gc_older_than() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    synthetic_cache(
+        cache.path(),
+        &[
+            ("current-but-old", 1024, 90),
+            ("old", 2048, 60),
+            ("recent", 1024, 1),
+        ],
+    );
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-gc", "--older-than=30d"])
+        .assert()
+        .success();
+    let buckle_dir = cache.path().join("buckle");
+    assert!(buckle_dir.join("current-but-old").exists());
+    assert!(!buckle_dir.join("old").exists());
+    assert!(!buckle_dir.join("old.lock").exists());
+    assert!(buckle_dir.join("recent").exists());
+    let err = stderr(&assert);
+    assert!(
+        err.contains("removed 1 cached version(s), reclaimed 2.0 KiB"),
+        "found {err}"
+    );
+    // Nothing was downloaded.
+    assert!(!buckle_dir.join(VERSION).exists());
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-gc", "--older-than=30 days"])
+        .assert()
+        .failure();
+    let err = stderr(&assert);
+    assert!(err.contains("invalid age '30 days'"), "found {err}");
+}
+
+/// `--buckle-cache-list` shows every cached version, most recently used first, by the tag recorded
+/// in its `source.json`.
+#[test]
+fn test_cache_list() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let buckle_dir = cache.path().join("buckle");
+    // Written first, since writing it counts as a use of the version.
+    std::fs::create_dir_all(buckle_dir.join("old")).unwrap();
+    std::fs::write(
+        buckle_dir.join("old").join("source.json"),
+        r#"{"url": "https://example.com/buck2.zst", "tag": "2024-01-01", "downloaded_at": 0, "checksum": null}"#,
+    )
+    .unwrap();
+    synthetic_cache(
+        cache.path(),
+        &[("in-use", 1024, 10), ("old", 2048, 60), ("recent", 512, 1)],
+    );
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .arg("--buckle-cache-list")
+        .assert()
+        .success();
+    let out = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 3, "found {out}");
+    assert!(lines[0].starts_with("recent "), "found {out}");
+    assert!(lines[0].contains("512 B  last used 1d ago"), "found {out}");
+    assert!(lines[1].starts_with("in-use "), "found {out}");
+    assert!(lines[1].ends_with(" (current)"), "found {out}");
+    assert!(lines[2].starts_with("2024-01-01 "), "found {out}");
+    assert!(lines[2].contains("last used 8w ago"), "found {out}");
+    assert!(
+        lines[2].contains(&buckle_dir.join("old").display().to_string()),
+        "found {out}"
+    );
+    // Nothing was downloaded.
+    assert!(!buckle_dir.join(VERSION).exists());
+}
+
+/// `--buckle-cache-remove` removes one version by its tag, but not the current one.
+#[test]
+fn test_cache_remove() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    synthetic_cache(
+        cache.path(),
+        &[("in-use", 1024, 10), ("old", 2048, 60), ("recent", 512, 1)],
+    );
+    let buckle_dir = cache.path().join("buckle");
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-cache-remove", "old"])
+        .assert()
+        .success();
+    assert!(!buckle_dir.join("old").exists());
+    assert!(!buckle_dir.join("old.lock").exists());
+    assert!(buckle_dir.join("recent").exists());
+    let err = stderr(&assert);
+    assert!(err.contains("(2.0 KiB)"), "found {err}");
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-cache-remove", "in-use"])
+        .assert()
+        .failure();
+    assert!(buckle_dir.join("in-use").exists());
+    let err = stderr(&assert);
+    assert!(
+        err.contains("buck2 in-use is the current version, so it can't be removed"),
+        "found {err}"
+    );
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-cache-remove", "old"])
+        .assert()
+        .failure();
+    let err = stderr(&assert);
+    assert!(err.contains("buck2 old is not cached"), "found {err}");
+}
+
+/// `--buckle-gc --max-size` removes the least recently used versions until the cache fits.
+#[test]
+fn test_gc_max_size() {
+    let server = MockServer::buck2(VERSION);
+    let project = tempfile::tempdir().unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    synthetic_cache(
+        cache.path(),
+        &[
+            ("in-use", 4096, 10),
+            ("oldest", 4096, 5),
+            ("older", 4096, 3),
+            ("newest", 4096, 1),
+        ],
+    );
+
+    let assert = buckle(project.path(), cache.path(), &server)
+        .args(["--buckle-gc", "--max-size=10K"])
+        .assert()
+        .success();
+    let buckle_dir = cache.path().join("buckle");
+    // The current version counts toward the size, but is kept however old it is.
+    assert!(buckle_dir.join("in-use").exists());
+    assert!(!buckle_dir.join("oldest").exists());
+    assert!(!buckle_dir.join("older").exists());
+    assert!(buckle_dir.join("newest").exists());
+    let err = stderr(&assert);
+    assert!(
+        err.contains("removed 2 cached version(s), reclaimed 8.0 KiB"),
+        "found {err}"
+    );
+}
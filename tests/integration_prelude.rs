@@ -0,0 +1,222 @@
+//! Integration tests for checking the prelude submodule against the buck2 release.
+#![cfg(unix)]
+
+mod common;
+
+use common::{buckle, prelude_project, MockServer, VERSION};
+
+fn stderr(assert: &assert_cmd::assert::Assert) -> String {
+    String::from_utf8_lossy(&assert.get_output().stderr).to_string()
+}
+
+/// A prelude submodule at the wrong commit is reported.
+#[test]
+fn test_prelude_mismatch_warns() {
+    let server = MockServer::buck2(VERSION);
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let prelude = prelude_project(&project);
+    let head = &prelude.head;
+
+    let assert = buckle(&project, cache.path(), &server)
+        .arg("--version")
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    assert!(
+        err.contains(&format!(
+            "Git submodule for prelude ({head}) is not the expected {}",
+            common::PRELUDE_HASH
+        )),
+        "found {err}"
+    );
+}
+
+/// With `BUCKLE_CI=github` the mismatch is reported as GitHub Actions warning annotations.
+#[test]
+fn test_prelude_mismatch_github_annotation() {
+    let server = MockServer::buck2(VERSION);
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let prelude = prelude_project(&project);
+    let head = &prelude.head;
+
+    let assert = buckle(&project, cache.path(), &server)
+        .env("BUCKLE_CI", "github")
+        .arg("--version")
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    let lines: Vec<&str> = err.lines().collect();
+    let mismatch = format!(
+        "::warning::buckle: Git submodule for prelude ({head}) is not the expected {}.",
+        common::PRELUDE_HASH
+    );
+    assert!(lines.contains(&mismatch.as_str()), "found {err}");
+    assert!(
+        lines
+            .iter()
+            .any(|line| line.starts_with("::warning::buckle: cd ")
+                && line.ends_with(&format!("git checkout {}", common::PRELUDE_HASH))),
+        "found {err}"
+    );
+    // Informational messages are printed as usual.
+    assert!(
+        lines.contains(&format!("buckle: fetching buck2 {VERSION}").as_str()),
+        "found {err}"
+    );
+}
+
+/// With `BUCKLE_PRELUDE_STRICT` a mismatched prelude stops buckle before buck2 runs.
+#[test]
+fn test_prelude_mismatch_strict_fails() {
+    let server = MockServer::buck2(VERSION);
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let prelude = prelude_project(&project);
+    let head = &prelude.head;
+
+    let assert = buckle(&project, cache.path(), &server)
+        .env("BUCKLE_PRELUDE_STRICT", "1")
+        .arg("--version")
+        .assert()
+        .code(69);
+    let err = stderr(&assert);
+    assert!(
+        err.contains(&format!(
+            "Git submodule for prelude ({head}) is not the expected {}",
+            common::PRELUDE_HASH
+        )),
+        "found {err}"
+    );
+    assert!(
+        err.contains(&format!("git checkout {}", common::PRELUDE_HASH)),
+        "found {err}"
+    );
+    assert!(assert.get_output().stdout.is_empty());
+}
+
+/// `--buckle-no-prelude-check` skips the check for one run and isn't passed on to buck2.
+#[test]
+fn test_no_prelude_check_flag() {
+    let server = MockServer::buck2(VERSION);
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let _prelude = prelude_project(&project);
+
+    let assert = buckle(&project, cache.path(), &server)
+        .args(["--buckle-no-prelude-check", "build", "//..."])
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    assert!(!err.contains("Git submodule for prelude"), "found {err}");
+    let out = String::from_utf8_lossy(&assert.get_output().stdout).to_string();
+    assert_eq!(out.trim(), "buck2 fake build //...");
+}
+
+/// A cache populated from a different mirror is called out before comparing its prelude hash.
+#[test]
+fn test_stale_prelude_metadata_warns() {
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let _prelude = prelude_project(&project);
+
+    let first = MockServer::buck2(VERSION);
+    let assert = buckle(&project, cache.path(), &first)
+        .arg("--version")
+        .assert()
+        .success();
+    assert!(!stderr(&assert).contains("was downloaded from"));
+
+    let second = MockServer::buck2(VERSION);
+    let assert = buckle(&project, cache.path(), &second)
+        .arg("--version")
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    assert!(
+        err.contains(&format!(
+            "the cached prelude hash for buck2 {VERSION} was downloaded from {}, but this project downloads from {}",
+            first.url(),
+            second.url()
+        )),
+        "found {err}"
+    );
+}
+
+/// Each prelude cell is checked against its own expected hash, and only the mismatched one is
+/// reported.
+#[test]
+fn test_several_preludes_checked() {
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let prelude =
+        common::prelude_project_with(&project, &["prelude", "vendor/prelude", "other/prelude"]);
+    let head = &prelude.head;
+    std::fs::write(
+        project.join(".buckconfig"),
+        "[cells]\nroot = .\nprelude = prelude\nvendor = vendor/prelude\nother_prelude = other/prelude\n",
+    )
+    .unwrap();
+    // `prelude` is expected at its own hash rather than the release's, and `other_prelude` has no
+    // expected hash, so only `vendor` is out of date.
+    common::write_buckleconfig(
+        &project,
+        &format!("[prelude_hashes]\nprelude = \"{head}\"\nvendor = \"{head}\"\n"),
+    );
+    for prelude in ["vendor/prelude", "other/prelude"] {
+        common::git(
+            &project.join(prelude),
+            &["commit", "-q", "--allow-empty", "-m", "local change"],
+        );
+    }
+
+    let server = MockServer::buck2(VERSION);
+    let assert = buckle(&project, cache.path(), &server)
+        .arg("--version")
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    assert_eq!(
+        err.matches("Git submodule for prelude").count(),
+        1,
+        "found {err}"
+    );
+    assert!(err.contains("vendor/prelude && git fetch"), "found {err}");
+}
+
+/// A release whose prelude hash is abbreviated or upper case still matches the submodule.
+#[test]
+fn test_abbreviated_prelude_hash_matches() {
+    let root = tempfile::tempdir().unwrap();
+    let project = root.path().join("project");
+    std::fs::create_dir(&project).unwrap();
+    let cache = tempfile::tempdir().unwrap();
+    let prelude = prelude_project(&project);
+    let head = &prelude.head;
+    let mut routes = common::buck2_routes(VERSION);
+    routes.insert(
+        format!("/{VERSION}/prelude_hash"),
+        common::Response::ok(format!("{}\n", head[..12].to_uppercase())),
+    );
+    let server = MockServer::with_routes(routes);
+
+    let assert = buckle(&project, cache.path(), &server)
+        .arg("--version")
+        .assert()
+        .success();
+    let err = stderr(&assert);
+    assert!(!err.contains("Git submodule for prelude"), "found {err}");
+}